@@ -0,0 +1,251 @@
+//! Authenticated HTTP API for inspecting and manipulating the multiplexer's state.
+use crate::{
+    jwt::{verify_single_token, Secret},
+    types::{JsonForkchoiceStateV1, JsonPayloadStatusV1, JsonValue, TransparentJsonPayloadId},
+    AppState,
+};
+use axum::{
+    extract::{Path, State},
+    headers::{authorization::Bearer, Authorization},
+    http::{Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Json, Router, TypedHeader,
+};
+use eth2::types::{EthSpec, ExecutionBlockHash};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+type AdminError = (StatusCode, String);
+
+/// Caches which can be inspected and cleared via the admin API.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Cache {
+    Fcu,
+    NewPayload,
+    Justified,
+    Finalized,
+    PayloadBuilder,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FcuEntry {
+    pub forkchoice_state: JsonForkchoiceStateV1,
+    /// Block number of the head block, if its newPayload is cached.
+    pub head_block_number: Option<u64>,
+    pub status: JsonPayloadStatusV1,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NewPayloadEntry {
+    pub block_hash: ExecutionBlockHash,
+    pub block_number: u64,
+    pub status: JsonPayloadStatusV1,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClientStatus {
+    pub clv: Option<String>,
+    /// Unix timestamp of the client's most recent request.
+    pub last_seen: Option<u64>,
+    pub capabilities: Option<Vec<String>>,
+}
+
+/// Routes for the admin API, all of which require a JWT signed with `--admin-jwt-secret`.
+pub fn router<E: EthSpec>(state: Arc<AppState<E>>, secret: Secret) -> Router<Arc<AppState<E>>> {
+    Router::new()
+        .route("/engines", get(handle_engines::<E>))
+        .route("/clients", get(handle_clients::<E>))
+        .route(
+            "/caches/:cache",
+            get(handle_get_cache::<E>).delete(handle_clear_cache::<E>),
+        )
+        .route("/caches/:cache/:key", delete(handle_evict::<E>))
+        .route_layer(middleware::from_fn_with_state(
+            (state, secret),
+            check_auth::<E, _>,
+        ))
+}
+
+async fn check_auth<E: EthSpec, B>(
+    State((state, secret)): State<(Arc<AppState<E>>, Secret)>,
+    TypedHeader(jwt_token_str): TypedHeader<Authorization<Bearer>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, AdminError> {
+    if let Err(e) = verify_single_token(jwt_token_str.token(), &secret)
+        .and_then(|token| state.check_iat(&token))
+    {
+        tracing::warn!(error = ?e, "Admin JWT auth failed");
+        return Err((StatusCode::UNAUTHORIZED, e));
+    }
+    Ok(next.run(request).await)
+}
+
+/// Parse a block hash or payload ID from a URL path.
+fn parse_key<T: DeserializeOwned>(key: String) -> Result<T, AdminError> {
+    serde_json::from_value(JsonValue::String(key))
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid key: {e}")))
+}
+
+async fn handle_engines<E: EthSpec>(State(state): State<Arc<AppState<E>>>) -> impl IntoResponse {
+    Json(state.multiplexer.engines.status())
+}
+
+async fn handle_clients<E: EthSpec>(State(state): State<Arc<AppState<E>>>) -> impl IntoResponse {
+    let clients = state
+        .multiplexer
+        .clients
+        .clients()
+        .into_iter()
+        .map(|(id, info)| {
+            let status = ClientStatus {
+                clv: info.clv,
+                last_seen: info
+                    .last_seen
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|d| d.as_secs()),
+                capabilities: info.capabilities,
+            };
+            (id, status)
+        })
+        .collect::<HashMap<_, _>>();
+    Json(clients)
+}
+
+/// Return the entries of a cache, from most to least recently used.
+async fn handle_get_cache<E: EthSpec>(
+    State(state): State<Arc<AppState<E>>>,
+    Path(cache): Path<Cache>,
+) -> Response {
+    let multiplexer = &state.multiplexer;
+    match cache {
+        Cache::Fcu => {
+            let entries = multiplexer
+                .fcu_cache
+                .lock()
+                .await
+                .iter()
+                .map(|(fcu, status)| (fcu.clone(), status.clone()))
+                .collect::<Vec<_>>();
+            let new_payload_cache = multiplexer.new_payload_cache.lock().await;
+            let entries = entries
+                .into_iter()
+                .map(|(forkchoice_state, status)| FcuEntry {
+                    head_block_number: new_payload_cache
+                        .peek(&forkchoice_state.head_block_hash)
+                        .map(|entry| entry.block_number),
+                    forkchoice_state,
+                    status,
+                })
+                .collect::<Vec<_>>();
+            Json(entries).into_response()
+        }
+        Cache::NewPayload => {
+            let entries = multiplexer
+                .new_payload_cache
+                .lock()
+                .await
+                .iter()
+                .map(|(block_hash, entry)| NewPayloadEntry {
+                    block_hash: *block_hash,
+                    block_number: entry.block_number,
+                    status: entry.status.clone(),
+                })
+                .collect::<Vec<_>>();
+            Json(entries).into_response()
+        }
+        Cache::Justified => {
+            let cache = multiplexer.justified_block_cache.lock().await;
+            Json(cache.iter().map(|(hash, _)| *hash).collect::<Vec<_>>()).into_response()
+        }
+        Cache::Finalized => {
+            let cache = multiplexer.finalized_block_cache.lock().await;
+            Json(cache.iter().map(|(hash, _)| *hash).collect::<Vec<_>>()).into_response()
+        }
+        Cache::PayloadBuilder => {
+            let payloads = multiplexer
+                .payload_builder
+                .lock()
+                .await
+                .registered_payloads();
+            Json(payloads).into_response()
+        }
+    }
+}
+
+async fn handle_clear_cache<E: EthSpec>(
+    State(state): State<Arc<AppState<E>>>,
+    Path(cache): Path<Cache>,
+) -> StatusCode {
+    let multiplexer = &state.multiplexer;
+    match cache {
+        Cache::Fcu => multiplexer.fcu_cache.lock().await.clear(),
+        Cache::NewPayload => multiplexer.new_payload_cache.lock().await.clear(),
+        Cache::Justified => multiplexer.justified_block_cache.lock().await.clear(),
+        Cache::Finalized => multiplexer.finalized_block_cache.lock().await.clear(),
+        Cache::PayloadBuilder => multiplexer.payload_builder.lock().await.clear_payloads(),
+    }
+    tracing::info!(?cache, "cache cleared via admin API");
+    StatusCode::NO_CONTENT
+}
+
+/// Evict a single entry from a cache.
+///
+/// Entries are identified by block hash, except for the fcU cache where every entry with the given
+/// head block hash is evicted, and the payload builder where entries are identified by payload ID.
+async fn handle_evict<E: EthSpec>(
+    State(state): State<Arc<AppState<E>>>,
+    Path((cache, key)): Path<(Cache, String)>,
+) -> Result<StatusCode, AdminError> {
+    let multiplexer = &state.multiplexer;
+    let evicted = match cache {
+        Cache::Fcu => {
+            let head_block_hash: ExecutionBlockHash = parse_key(key.clone())?;
+            let mut fcu_cache = multiplexer.fcu_cache.lock().await;
+            let keys = fcu_cache
+                .iter()
+                .filter(|(fcu, _)| fcu.head_block_hash == head_block_hash)
+                .map(|(fcu, _)| fcu.clone())
+                .collect::<Vec<_>>();
+            for fcu in &keys {
+                fcu_cache.pop(fcu);
+            }
+            !keys.is_empty()
+        }
+        Cache::NewPayload => {
+            let block_hash: ExecutionBlockHash = parse_key(key.clone())?;
+            let mut cache = multiplexer.new_payload_cache.lock().await;
+            cache.pop(&block_hash).is_some()
+        }
+        Cache::Justified => {
+            let block_hash: ExecutionBlockHash = parse_key(key.clone())?;
+            let mut cache = multiplexer.justified_block_cache.lock().await;
+            cache.pop(&block_hash).is_some()
+        }
+        Cache::Finalized => {
+            let block_hash: ExecutionBlockHash = parse_key(key.clone())?;
+            let mut cache = multiplexer.finalized_block_cache.lock().await;
+            cache.pop(&block_hash).is_some()
+        }
+        Cache::PayloadBuilder => {
+            let TransparentJsonPayloadId(payload_id) = parse_key(key.clone())?;
+            let mut builder = multiplexer.payload_builder.lock().await;
+            builder.evict_payload(&payload_id)
+        }
+    };
+
+    if !evicted {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("{key} not found in {cache:?}"),
+        ));
+    }
+    tracing::info!(?cache, key, "cache entry evicted via admin API");
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,275 @@
+//! Tree of execution blocks learned from the controller's newPayload messages.
+//!
+//! The tree is used to match client fcUs whose safe/finalized blocks differ from those recently
+//! sent by the controller, e.g. because the client is lagging slightly behind on justification.
+use crate::types::JsonPayloadStatusV1Status;
+use eth2::types::ExecutionBlockHash;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Block {
+    pub parent_hash: ExecutionBlockHash,
+    pub block_number: u64,
+    pub status: JsonPayloadStatusV1Status,
+}
+
+pub struct BlockTree {
+    blocks: HashMap<ExecutionBlockHash, Block>,
+    /// Hashes of the blocks in `blocks`, indexed by block number.
+    by_number: BTreeMap<u64, Vec<ExecutionBlockHash>>,
+    /// Most recent finalized block that the tree was pruned to, which is never evicted.
+    finalized: Option<ExecutionBlockHash>,
+    /// Maximum number of blocks to retain, which bounds memory use during long periods of
+    /// non-finality.
+    max_blocks: usize,
+}
+
+impl BlockTree {
+    pub fn new(max_blocks: usize) -> Self {
+        Self {
+            blocks: HashMap::new(),
+            by_number: BTreeMap::new(),
+            finalized: None,
+            max_blocks,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Add a block, or update the status of a known block.
+    ///
+    /// A VALID status is never overwritten, as validity is final.
+    pub fn insert(&mut self, block_hash: ExecutionBlockHash, block: Block) {
+        if let Some(existing) = self.blocks.get_mut(&block_hash) {
+            if existing.status != JsonPayloadStatusV1Status::Valid {
+                existing.status = block.status;
+            }
+            return;
+        }
+        self.blocks.insert(block_hash, block);
+        self.by_number
+            .entry(block.block_number)
+            .or_default()
+            .push(block_hash);
+
+        if self.blocks.len() > self.max_blocks {
+            self.evict_oldest();
+        }
+    }
+
+    /// Drop the oldest block other than the finalized block.
+    ///
+    /// Descendants of the evicted block remain matchable until they are pruned.
+    fn evict_oldest(&mut self) {
+        let Some((block_number, block_hash)) = self
+            .by_number
+            .iter()
+            .flat_map(|(number, hashes)| hashes.iter().map(move |hash| (*number, *hash)))
+            .find(|(_, hash)| Some(*hash) != self.finalized)
+        else {
+            return;
+        };
+        self.blocks.remove(&block_hash);
+        if let Some(hashes) = self.by_number.get_mut(&block_number) {
+            hashes.retain(|hash| *hash != block_hash);
+            if hashes.is_empty() {
+                self.by_number.remove(&block_number);
+            }
+        }
+    }
+
+    /// Mark a block and all of its known ancestors as VALID.
+    ///
+    /// A VALID fcU from the execution engine implies that the whole chain up to the head is valid,
+    /// including blocks for which newPayload returned SYNCING or ACCEPTED.
+    pub fn mark_valid(&mut self, block_hash: ExecutionBlockHash) {
+        let mut current = block_hash;
+        while let Some(block) = self.blocks.get_mut(&current) {
+            if block.status == JsonPayloadStatusV1Status::Valid && current != block_hash {
+                break;
+            }
+            block.status = JsonPayloadStatusV1Status::Valid;
+            current = block.parent_hash;
+        }
+    }
+
+    /// Check whether `descendant` is a VALID block descending from (or equal to) `ancestor`.
+    ///
+    /// The zero hash is treated as the ancestor of every block, as it is used for the safe and
+    /// finalized blocks prior to finalization.
+    pub fn is_valid_descendant(
+        &self,
+        descendant: ExecutionBlockHash,
+        ancestor: ExecutionBlockHash,
+    ) -> bool {
+        let Some(block) = self.blocks.get(&descendant) else {
+            return false;
+        };
+        if block.status != JsonPayloadStatusV1Status::Valid {
+            return false;
+        }
+        if ancestor == ExecutionBlockHash::zero() {
+            return true;
+        }
+        let Some(ancestor_number) = self.blocks.get(&ancestor).map(|block| block.block_number)
+        else {
+            return false;
+        };
+
+        let mut current = descendant;
+        while let Some(block) = self.blocks.get(&current) {
+            if current == ancestor {
+                return true;
+            }
+            if block.block_number <= ancestor_number {
+                return false;
+            }
+            current = block.parent_hash;
+        }
+        false
+    }
+
+    /// Remove all blocks which do not descend from the finalized block.
+    ///
+    /// Does nothing if the finalized block is unknown, in which case the tree is still catching
+    /// up after a restart.
+    pub fn prune(&mut self, finalized_hash: ExecutionBlockHash) {
+        let Some(finalized_number) = self
+            .blocks
+            .get(&finalized_hash)
+            .map(|block| block.block_number)
+        else {
+            return;
+        };
+
+        // Visit blocks in ascending order of block number, so that each block's parent is visited
+        // before the block itself.
+        let mut keep = HashSet::from([finalized_hash]);
+        for (_, hashes) in self.by_number.range(finalized_number + 1..) {
+            for hash in hashes {
+                if keep.contains(&self.blocks[hash].parent_hash) {
+                    keep.insert(*hash);
+                }
+            }
+        }
+
+        let num_before = self.blocks.len();
+        self.blocks.retain(|hash, _| keep.contains(hash));
+        self.by_number.retain(|_, hashes| {
+            hashes.retain(|hash| keep.contains(hash));
+            !hashes.is_empty()
+        });
+        self.finalized = Some(finalized_hash);
+        tracing::debug!(
+            finalized_hash = ?finalized_hash,
+            pruned = num_before - self.blocks.len(),
+            remaining = self.blocks.len(),
+            "pruned block tree"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use JsonPayloadStatusV1Status::{Syncing, Valid};
+
+    fn hash(n: u8) -> ExecutionBlockHash {
+        ExecutionBlockHash::repeat_byte(n)
+    }
+
+    /// Insert a block with hash `n` whose parent has hash `parent`.
+    fn insert(tree: &mut BlockTree, n: u8, parent: u8, block_number: u64) {
+        let block = Block {
+            parent_hash: hash(parent),
+            block_number,
+            status: Syncing,
+        };
+        tree.insert(hash(n), block);
+    }
+
+    /// A chain of blocks 1..=n, each the parent of the next.
+    fn chain(max_blocks: usize, n: u8) -> BlockTree {
+        let mut tree = BlockTree::new(max_blocks);
+        for i in 1..=n {
+            insert(&mut tree, i, i - 1, i.into());
+        }
+        tree
+    }
+
+    #[test]
+    fn insert_keeps_valid_status() {
+        let mut tree = chain(16, 1);
+        tree.mark_valid(hash(1));
+        insert(&mut tree, 1, 0, 1);
+        assert_eq!(tree.len(), 1);
+        assert!(tree.is_valid_descendant(hash(1), ExecutionBlockHash::zero()));
+    }
+
+    #[test]
+    fn evicts_oldest_block() {
+        let mut tree = chain(3, 4);
+        assert_eq!(tree.len(), 3);
+        tree.mark_valid(hash(4));
+        assert!(!tree.is_valid_descendant(hash(1), ExecutionBlockHash::zero()));
+        assert!(tree.is_valid_descendant(hash(4), hash(2)));
+    }
+
+    #[test]
+    fn never_evicts_finalized_block() {
+        let mut tree = chain(3, 3);
+        tree.prune(hash(1));
+        insert(&mut tree, 4, 3, 4);
+        insert(&mut tree, 5, 4, 5);
+        assert_eq!(tree.len(), 3);
+        tree.mark_valid(hash(1));
+        tree.mark_valid(hash(5));
+        assert!(tree.is_valid_descendant(hash(1), ExecutionBlockHash::zero()));
+        assert!(tree.is_valid_descendant(hash(5), hash(4)));
+        assert!(!tree.is_valid_descendant(hash(2), ExecutionBlockHash::zero()));
+        assert!(!tree.is_valid_descendant(hash(3), ExecutionBlockHash::zero()));
+    }
+
+    #[test]
+    fn prune_removes_non_descendants() {
+        // 1 <- 2 <- 3 and a fork 1 <- 4 <- 5.
+        let mut tree = chain(16, 3);
+        insert(&mut tree, 4, 1, 2);
+        insert(&mut tree, 5, 4, 3);
+        tree.mark_valid(hash(3));
+        tree.mark_valid(hash(5));
+
+        tree.prune(hash(2));
+        assert_eq!(tree.len(), 2);
+        assert!(tree.is_valid_descendant(hash(3), hash(2)));
+        assert!(!tree.is_valid_descendant(hash(5), ExecutionBlockHash::zero()));
+    }
+
+    #[test]
+    fn prune_unknown_finalized_block() {
+        let mut tree = chain(16, 3);
+        tree.prune(hash(9));
+        assert_eq!(tree.len(), 3);
+    }
+
+    #[test]
+    fn valid_descendants() {
+        let mut tree = chain(16, 4);
+        insert(&mut tree, 5, 2, 3);
+        tree.mark_valid(hash(3));
+
+        assert!(tree.is_valid_descendant(hash(3), hash(3)));
+        assert!(tree.is_valid_descendant(hash(3), hash(1)));
+        assert!(tree.is_valid_descendant(hash(3), ExecutionBlockHash::zero()));
+        // Not VALID.
+        assert!(!tree.is_valid_descendant(hash(4), hash(1)));
+        // Not an ancestor.
+        assert!(!tree.is_valid_descendant(hash(1), hash(3)));
+        tree.mark_valid(hash(5));
+        assert!(!tree.is_valid_descendant(hash(5), hash(3)));
+        // Unknown ancestor.
+        assert!(!tree.is_valid_descendant(hash(3), hash(9)));
+    }
+}
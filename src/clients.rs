@@ -0,0 +1,130 @@
+//! Registry of the consensus clients which have connected to eleel.
+use crate::metrics;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    /// Client version reported in the `clv` claim of the client's most recent JWT.
+    pub clv: Option<String>,
+    /// Time of the client's most recent request.
+    pub last_seen: SystemTime,
+    /// Engine API methods advertised by the client in its most recent
+    /// `engine_exchangeCapabilities` request.
+    pub capabilities: Option<Vec<String>>,
+}
+
+pub struct ClientRegistry {
+    clients: Mutex<HashMap<String, ClientInfo>>,
+    /// Key ID of the active controller, whose capabilities other clients are checked against.
+    active_controller: Mutex<String>,
+}
+
+impl Default for ClientRegistry {
+    fn default() -> Self {
+        Self {
+            clients: Mutex::default(),
+            active_controller: Mutex::new(metrics::CONTROLLER.to_string()),
+        }
+    }
+}
+
+impl ClientRegistry {
+    /// Record a request from the client with key ID `client_id`.
+    pub fn record(&self, client_id: &str, clv: Option<&str>) {
+        let mut clients = self.clients.lock().unwrap();
+        let last_seen = SystemTime::now();
+
+        let old_clv = match clients.get_mut(client_id) {
+            Some(info) => {
+                info.last_seen = last_seen;
+                if info.clv.as_deref() == clv {
+                    return;
+                }
+                std::mem::replace(&mut info.clv, clv.map(String::from))
+            }
+            None => {
+                clients.insert(
+                    client_id.to_string(),
+                    ClientInfo {
+                        clv: clv.map(String::from),
+                        last_seen,
+                        capabilities: None,
+                    },
+                );
+                None
+            }
+        };
+
+        tracing::info!(client_id, clv, old_clv, "client version");
+        metrics::set_client_version(client_id, old_clv.as_deref(), clv);
+    }
+
+    /// Record the capabilities advertised by a client, and check them against the controller's.
+    pub fn record_capabilities(&self, client_id: &str, capabilities: Vec<String>) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(info) = clients.get_mut(client_id) {
+            info.capabilities = Some(capabilities);
+        }
+
+        // If the controller's capabilities changed then all clients need re-checking.
+        let controller = self.active_controller.lock().unwrap().clone();
+        let only_client = (client_id != controller).then_some(client_id);
+        check_capabilities(&clients, &controller, only_client);
+    }
+
+    /// Record the key ID of the active controller, re-checking all clients if it has changed.
+    pub fn set_active_controller(&self, controller: &str) {
+        let mut active_controller = self.active_controller.lock().unwrap();
+        if *active_controller == controller {
+            return;
+        }
+        *active_controller = controller.to_string();
+        drop(active_controller);
+
+        let clients = self.clients.lock().unwrap();
+        check_capabilities(&clients, controller, None);
+    }
+
+    /// Snapshot of all clients seen so far, keyed by key ID.
+    pub fn clients(&self) -> HashMap<String, ClientInfo> {
+        self.clients.lock().unwrap().clone()
+    }
+}
+
+/// Check the capabilities of clients against those of `controller`, warning about any missing
+/// methods. Only `only_client` is checked, if set.
+fn check_capabilities(
+    clients: &HashMap<String, ClientInfo>,
+    controller: &str,
+    only_client: Option<&str>,
+) {
+    let Some(controller_capabilities) = clients
+        .get(controller)
+        .and_then(|info| info.capabilities.as_ref())
+    else {
+        return;
+    };
+
+    for (id, info) in clients {
+        if id == controller || only_client.is_some_and(|client_id| id != client_id) {
+            continue;
+        }
+        let Some(capabilities) = &info.capabilities else {
+            continue;
+        };
+        let missing = controller_capabilities
+            .iter()
+            .filter(|method| !capabilities.contains(method))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            tracing::warn!(
+                client_id = id,
+                ?missing,
+                "client lacks engine methods used by the controller"
+            );
+        }
+        metrics::set_missing_capabilities(id, missing.len());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::method_pattern::{glob_match, MethodPattern, MethodTimeout};
+use crate::rate_limit::{Limits, MethodClass};
 use clap::{builder::PossibleValue, Parser, ValueEnum};
 use eth2_network_config::Eth2NetworkConfig;
 use serde::{Deserialize, Serialize};
@@ -5,6 +7,7 @@ use std::collections::HashMap;
 use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use strum::{EnumString, IntoStaticStr};
 
 #[derive(Debug, Clone, Parser)]
@@ -16,20 +19,94 @@ pub struct Config {
     /// Listening port for the HTTP server.
     #[arg(long, value_name = "PORT", default_value = "8552")]
     pub listen_port: u16,
-    /// Primary execution engine to be shared by connected consensus nodes.
-    #[arg(long, value_name = "URL", default_value = "http://localhost:8551")]
-    pub ee_url: String,
-    /// Path to the JWT secret for the primary execution engine.
-    #[arg(long, value_name = "PATH")]
-    pub ee_jwt_secret: String,
-    /// Path to the JWT secret for the controlling consensus client.
-    #[arg(long, value_name = "PATH")]
-    pub controller_jwt_secret: PathBuf,
+    /// Execution engines to be shared by connected consensus nodes, as a comma-separated list.
+    ///
+    /// The first engine is the primary, and the others are backups in order of preference.
+    /// Requests are sent to the first healthy engine.
+    #[arg(
+        long,
+        value_name = "URLS",
+        default_value = "http://localhost:8551",
+        value_delimiter = ','
+    )]
+    pub ee_url: Vec<String>,
+    /// Paths to the JWT secrets for the execution engines, as a comma-separated list.
+    ///
+    /// Provide either a single secret shared by all engines, or one secret per engine in the same
+    /// order as `--ee-url`.
+    #[arg(long, value_name = "PATHS", required = true, value_delimiter = ',')]
+    pub ee_jwt_secret: Vec<String>,
+    /// Interval between health checks of the execution engines.
+    #[arg(long, value_name = "SECONDS", default_value = "5")]
+    pub ee_health_check_secs: u64,
+    /// Maximum number of requests to send to the execution engine concurrently.
+    ///
+    /// One of these slots is reserved for requests from the controlling consensus node, so that
+    /// client traffic can never delay the canonical chain.
+    #[arg(long, value_name = "N", default_value = "16")]
+    pub ee_max_concurrent_requests: usize,
+    /// Maximum number of requests of each priority to queue while waiting for the execution engine.
+    ///
+    /// Requests arriving while the queue is full are rejected immediately.
+    #[arg(long, value_name = "N", default_value = "256")]
+    pub ee_queue_size: usize,
+    /// Additional methods to proxy directly to the execution engine, as a comma-separated list.
+    ///
+    /// Patterns may contain `*` wildcards, e.g. `eth_getTransactionReceipt,debug_*`. Patterns which
+    /// could match `engine_forkchoiceUpdated*`, `engine_newPayload*` or `engine_getPayloadV*` are
+    /// rejected.
+    #[arg(
+        long,
+        value_name = "PATTERNS",
+        value_delimiter = ',',
+        value_parser = MethodPattern::parse_proxied
+    )]
+    pub proxy_methods: Vec<MethodPattern>,
+    /// Methods which must never be proxied to the execution engine, as a comma-separated list.
+    ///
+    /// Takes precedence over `--proxy-methods` and the methods proxied by default.
+    #[arg(long, value_name = "PATTERNS", value_delimiter = ',')]
+    pub proxy_deny: Vec<MethodPattern>,
+    /// Timeouts for proxied methods, as a comma-separated list of `PATTERN=MILLIS`.
+    ///
+    /// The first matching pattern applies. Methods without a match use
+    /// `--proxy-default-timeout-millis`.
+    #[arg(long, value_name = "TIMEOUTS", value_delimiter = ',')]
+    pub proxy_timeouts: Vec<MethodTimeout>,
+    /// Timeout for proxied methods which do not match any of `--proxy-timeouts`.
+    #[arg(long, value_name = "MILLIS", default_value = "12000")]
+    pub proxy_default_timeout_millis: u64,
+    /// Paths to the JWT secrets for the controlling consensus clients, as a comma-separated list.
+    ///
+    /// The first controller is the primary, and the others are standbys in order of preference.
+    /// A standby only takes control once every controller before it has been silent for
+    /// `--controller-failover-secs`.
+    #[arg(long, value_name = "PATHS", required = true, value_delimiter = ',')]
+    pub controller_jwt_secret: Vec<PathBuf>,
+    /// Time after the last request from a controller before a standby controller takes over.
+    #[arg(long, value_name = "SECONDS", default_value = "36")]
+    pub controller_failover_secs: u64,
     /// Path to TOML file of JWT secrets for the non-controlling consensus clients.
     ///
     /// See docs for TOML file format.
     #[arg(long, value_name = "PATH")]
     pub client_jwt_secrets: PathBuf,
+    /// Path to the JWT secret for the admin API.
+    ///
+    /// The `/admin` routes are disabled unless this is set.
+    #[arg(long, value_name = "PATH")]
+    pub admin_jwt_secret: Option<PathBuf>,
+    /// Maximum difference in seconds between the `iat` of a JWT token and the current time.
+    ///
+    /// Tokens issued further in the past or future are rejected, as required by the engine API
+    /// spec.
+    #[arg(long, value_name = "SECONDS", default_value = "60")]
+    pub jwt_iat_tolerance_secs: u64,
+    /// Disable checking of JWT `iat` claims.
+    ///
+    /// This is insecure, as captured tokens can be replayed indefinitely.
+    #[arg(long)]
+    pub disable_jwt_iat_check: bool,
     /// Number of recent newPayload messages to cache in memory.
     #[arg(long, value_name = "N", default_value = "64")]
     pub new_payload_cache_size: usize,
@@ -42,18 +119,58 @@ pub struct Config {
     /// Extra data to include in produced blocks.
     #[arg(long, value_name = "STRING", default_value = "Eleel")]
     pub payload_builder_extra_data: String,
+    /// Forward the controller's payload attributes to the execution engine.
+    ///
+    /// When enabled, `getPayload` requests are served using the real payload built by the
+    /// execution engine (with transactions, blobs and block value), falling back to a dummy
+    /// payload if the execution engine is unable to provide one. This may increase the load on
+    /// the execution engine.
+    #[arg(long)]
+    pub forward_payload_attributes: bool,
     /// Number of justified block hashes to cache in memory.
     #[arg(long, value_name = "N", default_value = "4")]
     pub justified_block_cache_size: usize,
     /// Number of finalized block hashes to cache in memory.
     #[arg(long, value_name = "N", default_value = "4")]
     pub finalized_block_cache_size: usize,
+    /// Maximum number of blocks from the controller to track for fcU matching.
+    ///
+    /// Blocks are pruned on finalization, so this limit only applies during long periods of
+    /// non-finality.
+    #[arg(long, value_name = "N", default_value = "8192")]
+    pub block_tree_max_blocks: usize,
     /// Choose the type of matching to use before returning a VALID fcU message to a client.
     #[arg(long, value_name = "NAME", default_value = "loose", value_enum)]
     pub fcu_matching: FcuMatching,
     /// Network that the consensus and execution nodes are operating on.
     #[arg(long, value_name = "NAME", default_value = "mainnet")]
     pub network: Network,
+    /// Path to a directory containing a custom network configuration.
+    ///
+    /// The directory should be in the same format as Lighthouse's `--testnet-dir`, i.e. containing
+    /// `config.yaml` and optionally `genesis.ssz`. The preset (mainnet/minimal/gnosis) is selected
+    /// using the `PRESET_BASE` from `config.yaml`. Conflicts with `--network`.
+    #[arg(long, value_name = "PATH", conflicts_with = "network")]
+    pub testnet_dir: Option<PathBuf>,
+    /// Genesis time of the network, in seconds since the UNIX epoch.
+    ///
+    /// Providing this allows Eleel to start without loading the genesis state.
+    #[arg(long, value_name = "SECONDS")]
+    pub genesis_time: Option<u64>,
+    /// Path to an SSZ-encoded genesis state from which to read the genesis time.
+    #[arg(long, value_name = "PATH", conflicts_with = "genesis_time")]
+    pub genesis_state: Option<PathBuf>,
+    /// Compute the genesis time as `MIN_GENESIS_TIME + GENESIS_DELAY` from the network config.
+    ///
+    /// This is correct for most devnets, but *not* for mainnet or the long-lived public testnets.
+    #[arg(long, conflicts_with_all = ["genesis_time", "genesis_state"])]
+    pub genesis_time_from_config: bool,
+    /// URL from which to download the genesis state if it is not known by other means.
+    ///
+    /// The genesis state is only downloaded if none of `--genesis-time`, `--genesis-state` or
+    /// `--genesis-time-from-config` are provided, and the network config does not include it.
+    #[arg(long, value_name = "URL")]
+    pub genesis_state_url: Option<String>,
     /// Maximum time that a consensus node should wait for a newPayload response from the cache.
     ///
     /// We expect that the controlling consensus node and primary execution node will take some
@@ -63,7 +180,7 @@ pub struct Config {
     /// definitive (VALID) response from the execution engine to be returned, more closely matching
     /// the behaviour of a full execution engine.
     #[arg(long, value_name = "MILLIS", default_value = "2000")]
-    pub new_payload_wait_millis: u128,
+    pub new_payload_wait_millis: u64,
     /// Maximum age of a payload that will trigger a wait on `newPayload`
     ///
     /// Payloads older than this age receive an instant SYNCING response. See docs for
@@ -75,15 +192,162 @@ pub struct Config {
     ///
     /// See the docs for `--new-payload-wait-millis` for the purpose of this timeout.
     #[arg(long, value_name = "MILLIS", default_value = "1000")]
-    pub fcu_wait_millis: u128,
+    pub fcu_wait_millis: u64,
+    /// Number of seconds for which the execution engine's response to `eth_syncing` is cached.
+    #[arg(long, value_name = "SECONDS", default_value = "2")]
+    pub syncing_cache_secs: u64,
+    /// Report `eth_syncing` as syncing if the controller hasn't sent an fcU for this many seconds.
+    ///
+    /// Without regular fcUs from the controller the execution engine's view of the chain is
+    /// likely to be stale, even if the execution engine itself reports that it is synced.
+    #[arg(long, value_name = "SECONDS", default_value = "36")]
+    pub controller_fcu_stale_secs: u64,
+    /// Path to a file in which to persist cached statuses across restarts.
+    ///
+    /// The file is written periodically and on shutdown, and loaded at startup. Persistence is
+    /// disabled if this is not set.
+    #[arg(long, value_name = "PATH")]
+    pub persist_path: Option<PathBuf>,
+    /// Interval between writes of the `--persist-path` file.
+    #[arg(long, value_name = "SECONDS", default_value = "60")]
+    pub persist_interval_secs: u64,
+    /// Maximum time to wait for in-flight requests to complete when shutting down.
+    #[arg(long, value_name = "SECONDS", default_value = "15")]
+    pub shutdown_timeout_secs: u64,
     /// Maximum size of JSON-RPC message to accept from any connected consensus node.
     #[arg(long, value_name = "MEGABYTES", default_value = "128")]
     pub body_limit_mb: usize,
+    /// Maximum number of requests to accept in a single JSON-RPC batch.
+    ///
+    /// Larger batches are rejected with a JSON-RPC error.
+    #[arg(long, value_name = "N", default_value = "100")]
+    pub max_batch_size: usize,
+    /// Maximum number of requests from a single client batch to process concurrently.
+    ///
+    /// Responses are always returned in the same order as the requests. Batches sent by the
+    /// controller are processed sequentially regardless of this setting.
+    #[arg(long, value_name = "N", default_value = "16")]
+    pub batch_concurrency: usize,
+}
+
+impl Config {
+    /// Load the network config from `--testnet-dir` if provided, or use `--network` otherwise.
+    pub fn network_config(&self) -> Result<Eth2NetworkConfig, String> {
+        match &self.testnet_dir {
+            Some(testnet_dir) => Eth2NetworkConfig::load(testnet_dir.clone()).map_err(|e| {
+                format!(
+                    "Unable to load network config from {}: {e}",
+                    testnet_dir.display()
+                )
+            }),
+            None => Ok(self.network.network.clone()),
+        }
+    }
+
+    /// Check whether `method` was configured to be proxied with `--proxy-methods`.
+    pub fn is_extra_proxy_method(&self, method: &str) -> bool {
+        self.proxy_methods
+            .iter()
+            .any(|pattern| pattern.matches(method))
+    }
+
+    /// Check whether `method` was forbidden from being proxied with `--proxy-deny`.
+    pub fn is_proxy_denied(&self, method: &str) -> bool {
+        self.proxy_deny
+            .iter()
+            .any(|pattern| pattern.matches(method))
+    }
+
+    /// Timeout for proxying a request for `method` to the execution engine.
+    pub fn proxy_timeout(&self, method: &str) -> Duration {
+        self.proxy_timeouts
+            .iter()
+            .find(|timeout| timeout.pattern.matches(method))
+            .map_or(
+                Duration::from_millis(self.proxy_default_timeout_millis),
+                |timeout| timeout.timeout,
+            )
+    }
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct ClientJwtSecrets {
     pub secrets: HashMap<String, String>,
+    /// Optional per-client settings, keyed by the same IDs as `secrets`.
+    #[serde(default)]
+    pub clients: HashMap<String, ClientSettings>,
+    /// Limits applied to clients which do not set their own limits for a class of methods.
+    #[serde(default)]
+    pub default_limits: HashMap<MethodClass, Limits>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientSettings {
+    /// Reject all requests from this client.
+    #[serde(default)]
+    pub disabled: bool,
+    /// Named set of methods that this client may call.
+    pub role: Option<Role>,
+    /// Additional methods that this client may call (glob patterns).
+    pub methods: Option<Vec<MethodPattern>>,
+    /// Rate limits and concurrency caps for this client, by class of method.
+    #[serde(default)]
+    pub limits: HashMap<MethodClass, Limits>,
+}
+
+impl ClientSettings {
+    /// Check whether the client is permitted to call `method`.
+    ///
+    /// Clients without a `role` or `methods` may call any method. Otherwise, the method must be
+    /// permitted by either the role or the list of methods.
+    pub fn is_method_allowed(&self, method: &str) -> bool {
+        if self.role.is_none() && self.methods.is_none() {
+            return true;
+        }
+        let allowed_by_role = self.role.is_some_and(|role| role.is_method_allowed(method));
+        let allowed_by_methods = self
+            .methods
+            .iter()
+            .flatten()
+            .any(|pattern| pattern.matches(method));
+        allowed_by_role || allowed_by_methods
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// All methods.
+    Full,
+    /// Methods required by a consensus client, excluding arbitrary queries like `eth_call`.
+    EngineOnly,
+    /// Read-only query methods, excluding those which drive fork choice or block production.
+    Analytics,
+}
+
+impl Role {
+    pub fn is_method_allowed(self, method: &str) -> bool {
+        let patterns: &[&str] = match self {
+            Role::Full => &["*"],
+            Role::EngineOnly => &[
+                "engine_*",
+                "eth_chainId",
+                "eth_syncing",
+                "eth_blockNumber",
+                "eth_getBlockByNumber",
+                "eth_getBlockByHash",
+            ],
+            Role::Analytics => &[
+                "eth_*",
+                "engine_exchangeCapabilities",
+                "engine_getClientVersionV*",
+                "engine_getPayloadBodiesBy*",
+                "engine_getBlobsV*",
+            ],
+        };
+        patterns.iter().any(|pattern| glob_match(pattern, method))
+    }
 }
 
 impl ClientJwtSecrets {
@@ -115,8 +379,9 @@ impl FromStr for Network {
 pub enum FcuMatching {
     /// Client fcU must match a prior fcU from the controller *exactly*.
     Exact,
-    /// Client fcU must reference head, justified and finalized blocks from prior controller calls,
-    /// but do not necessarily have to match any single 3-tuple.
+    /// Client fcU must reference a head block from a prior controller call, which is a VALID
+    /// descendant of the justified and finalized blocks. Justified and finalized blocks from
+    /// prior controller calls are also accepted, even if not known to be ancestors of the head.
     ///
     /// This admits some innocuous things like head blocks with old justification/finalization,
     /// but also some weird stuff like justification==finalization, which Prysm v4.0.0 and lower
@@ -149,3 +414,74 @@ impl ValueEnum for FcuMatching {
         Some(pv)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Option<Vec<MethodPattern>> {
+        Some(patterns.iter().map(|p| p.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn no_settings_allow_all() {
+        let settings = ClientSettings::default();
+        assert!(settings.is_method_allowed("engine_newPayloadV4"));
+        assert!(settings.is_method_allowed("debug_traceBlock"));
+    }
+
+    #[test]
+    fn role_only() {
+        let settings = ClientSettings {
+            role: Some(Role::Analytics),
+            ..Default::default()
+        };
+        assert!(settings.is_method_allowed("eth_getLogs"));
+        assert!(settings.is_method_allowed("engine_getPayloadBodiesByRangeV1"));
+        assert!(!settings.is_method_allowed("engine_forkchoiceUpdatedV3"));
+        assert!(!settings.is_method_allowed("debug_traceBlock"));
+    }
+
+    #[test]
+    fn methods_only() {
+        let settings = ClientSettings {
+            methods: patterns(&["eth_getLogs", "debug_*"]),
+            ..Default::default()
+        };
+        assert!(settings.is_method_allowed("eth_getLogs"));
+        assert!(settings.is_method_allowed("debug_traceBlock"));
+        assert!(!settings.is_method_allowed("eth_call"));
+        assert!(!settings.is_method_allowed("engine_newPayloadV4"));
+    }
+
+    #[test]
+    fn role_and_methods_union() {
+        let settings = ClientSettings {
+            role: Some(Role::EngineOnly),
+            methods: patterns(&["eth_call"]),
+            ..Default::default()
+        };
+        assert!(settings.is_method_allowed("engine_newPayloadV4"));
+        assert!(settings.is_method_allowed("eth_chainId"));
+        assert!(settings.is_method_allowed("eth_call"));
+        assert!(!settings.is_method_allowed("eth_getLogs"));
+    }
+
+    #[test]
+    fn empty_methods_deny_all() {
+        let settings = ClientSettings {
+            methods: Some(vec![]),
+            ..Default::default()
+        };
+        assert!(!settings.is_method_allowed("eth_chainId"));
+    }
+
+    #[test]
+    fn roles() {
+        assert!(Role::Full.is_method_allowed("debug_traceBlock"));
+        assert!(Role::EngineOnly.is_method_allowed("engine_getBlobsV1"));
+        assert!(!Role::EngineOnly.is_method_allowed("eth_call"));
+        assert!(Role::Analytics.is_method_allowed("engine_getClientVersionV1"));
+        assert!(!Role::Analytics.is_method_allowed("engine_newPayloadV4"));
+    }
+}
@@ -0,0 +1,156 @@
+//! Failover between a primary controller and standby controllers.
+//!
+//! Only one controller is active at a time, and only the active controller's requests are
+//! forwarded to the EL. The active controller is the first controller (in the order given on the
+//! command line) which has sent a request within the failover threshold. Requests from inactive
+//! controllers are handled as if they came from a client.
+use crate::{
+    jwt::{verify_single_token, Secret, VerifiedToken},
+    metrics,
+};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub struct Controllers {
+    secrets: Vec<Secret>,
+    failover_threshold: Duration,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Time of the most recent request from each controller.
+    last_seen: Vec<Option<Instant>>,
+    /// Index of the active controller.
+    active: usize,
+}
+
+impl Controllers {
+    pub fn new(secrets: Vec<Secret>, failover_threshold: Duration) -> Self {
+        Self::new_at(secrets, failover_threshold, Instant::now())
+    }
+
+    fn new_at(secrets: Vec<Secret>, failover_threshold: Duration, now: Instant) -> Self {
+        // Treat the primary as having been seen at startup, so that standbys must wait for the
+        // failover threshold before taking over.
+        let mut last_seen = vec![None; secrets.len()];
+        last_seen[0] = Some(now);
+        Self {
+            secrets,
+            failover_threshold,
+            state: Mutex::new(State {
+                last_seen,
+                active: 0,
+            }),
+        }
+    }
+
+    /// Verify a token against each controller secret, returning the index of the matching one.
+    pub fn verify(&self, token: &str) -> Result<(usize, VerifiedToken), String> {
+        let mut last_error = None;
+        for (index, secret) in self.secrets.iter().enumerate() {
+            match verify_single_token(token, secret) {
+                Ok(token) => return Ok((index, token)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| "No controller JWT secrets".into()))
+    }
+
+    /// Record a request from the controller at `index`, returning `true` if it is active.
+    pub fn record_request(&self, index: usize) -> bool {
+        self.record_request_at(index, Instant::now())
+    }
+
+    fn record_request_at(&self, index: usize, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.last_seen[index] = Some(now);
+
+        let active = state
+            .last_seen
+            .iter()
+            .position(|last_seen| {
+                last_seen.is_some_and(|t| now.duration_since(t) <= self.failover_threshold)
+            })
+            .unwrap_or(index);
+
+        if active != state.active {
+            let from = Self::name(state.active);
+            let to = Self::name(active);
+            if active < state.active {
+                tracing::info!(from, to, "handing control back to preferred controller");
+            } else {
+                tracing::warn!(
+                    from,
+                    to,
+                    silent_secs = self.failover_threshold.as_secs(),
+                    "controller silent, handing control to standby"
+                );
+            }
+            state.active = active;
+        }
+        index == active
+    }
+
+    /// Name of the controller at `index`, for logs and metrics.
+    pub fn name(index: usize) -> String {
+        if index == 0 {
+            metrics::CONTROLLER.to_string()
+        } else {
+            format!("{}-{index}", metrics::CONTROLLER)
+        }
+    }
+
+    /// Check whether `id` has the form of a controller name, and so can't be used as a client ID.
+    pub fn is_reserved_name(id: &str) -> bool {
+        match id.strip_prefix(metrics::CONTROLLER) {
+            Some("") => true,
+            Some(suffix) => suffix
+                .strip_prefix('-')
+                .is_some_and(|index| index.parse::<usize>().is_ok()),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hmac::Mac;
+
+    const THRESHOLD: Duration = Duration::from_secs(30);
+
+    fn controllers(n: usize, start: Instant) -> Controllers {
+        let secrets = (0..n)
+            .map(|i| Secret::new_from_slice(&[i as u8; 32]).unwrap())
+            .collect();
+        Controllers::new_at(secrets, THRESHOLD, start)
+    }
+
+    #[test]
+    fn primary_active_at_startup() {
+        let start = Instant::now();
+        let controllers = controllers(2, start);
+        assert!(!controllers.record_request_at(1, start + Duration::from_secs(1)));
+        assert!(!controllers.record_request_at(1, start + THRESHOLD));
+        assert!(controllers.record_request_at(0, start + THRESHOLD));
+    }
+
+    #[test]
+    fn standby_takes_over_after_threshold() {
+        let start = Instant::now();
+        let controllers = controllers(3, start);
+        let later = start + THRESHOLD + Duration::from_secs(1);
+        assert!(controllers.record_request_at(1, later));
+        assert!(!controllers.record_request_at(2, later));
+    }
+
+    #[test]
+    fn primary_takes_back_control() {
+        let start = Instant::now();
+        let controllers = controllers(2, start);
+        let later = start + THRESHOLD + Duration::from_secs(1);
+        assert!(controllers.record_request_at(1, later));
+        assert!(controllers.record_request_at(0, later + Duration::from_secs(1)));
+        assert!(!controllers.record_request_at(1, later + Duration::from_secs(2)));
+    }
+}
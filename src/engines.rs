@@ -0,0 +1,364 @@
+//! Management of multiple upstream execution engines with failover.
+//!
+//! Requests are sent to a single active engine. A background task checks the health of every
+//! engine, and if the active engine becomes unhealthy we switch to the first healthy engine (in
+//! the order given on the command line). Before switching, recent payloads and the latest fcU from
+//! the controller are replayed to the new engine so that it can serve the canonical chain.
+use crate::{
+    config::Config,
+    metrics,
+    multiplexer::Multiplexer,
+    types::{Auth, Engine, JsonValue, TaskExecutor},
+};
+use eth2::types::{ChainSpec, EthSpec};
+use execution_layer::{
+    http::{ENGINE_FORKCHOICE_UPDATED_TIMEOUT, ENGINE_NEW_PAYLOAD_TIMEOUT},
+    HttpJsonRpc,
+};
+use serde::Serialize;
+use slog::Logger;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Number of recent controller payloads to replay to a new engine on failover.
+const REPLAY_NEW_PAYLOADS: usize = 32;
+
+const CHAIN_ID_ATTEMPTS: u32 = 30;
+const CHAIN_ID_RETRY_DELAY_SECS: u64 = 2;
+const CHAIN_ID_TIMEOUT_SECS: u64 = 5;
+
+pub struct Engines {
+    engines: Vec<UpstreamEngine>,
+    /// Index of the engine that requests are currently sent to.
+    active: AtomicUsize,
+    replay: Mutex<ReplayBuffer>,
+}
+
+pub struct UpstreamEngine {
+    pub url: String,
+    pub engine: Engine,
+    /// Chain ID of the engine, once it has been checked against the network.
+    chain_id: Mutex<Option<u64>>,
+    healthy: AtomicBool,
+    status: Mutex<HealthStatus>,
+}
+
+#[derive(Default)]
+struct HealthStatus {
+    last_checked: Option<SystemTime>,
+    last_error: Option<String>,
+}
+
+/// Recent requests from the controller, stored as raw JSON-RPC method and params.
+#[derive(Default)]
+struct ReplayBuffer {
+    new_payloads: VecDeque<(String, JsonValue)>,
+    fcu: Option<(String, JsonValue)>,
+}
+
+/// Status of an upstream engine, as returned by the admin API.
+#[derive(Debug, Serialize)]
+pub struct EngineStatus {
+    pub url: String,
+    pub active: bool,
+    pub healthy: bool,
+    pub chain_id: Option<u64>,
+    /// Unix timestamp of the last health check.
+    pub last_checked: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl Engines {
+    pub fn new(config: &Config, executor: TaskExecutor, log: &Logger) -> Result<Self, String> {
+        let secrets = match (config.ee_url.len(), config.ee_jwt_secret.len()) {
+            (_, 1) => vec![&config.ee_jwt_secret[0]; config.ee_url.len()],
+            (urls, secrets) if urls == secrets => config.ee_jwt_secret.iter().collect(),
+            (urls, secrets) => {
+                return Err(format!(
+                    "{secrets} EL JWT secrets provided for {urls} ELs, expected 1 or {urls}"
+                ))
+            }
+        };
+
+        let engines = config
+            .ee_url
+            .iter()
+            .zip(secrets)
+            .map(|(url, jwt_secret_path)| {
+                let jwt_secret_path = PathBuf::from(jwt_secret_path);
+                let jwt_id = Some("eleel".to_string());
+                let jwt_version = None;
+
+                let execution_timeout_multiplier = Some(2);
+
+                let auth = Auth::new_with_path(jwt_secret_path, jwt_id, jwt_version)
+                    .map_err(|e| format!("JWT secret error for {url}: {e:?}"))?;
+
+                let parsed_url =
+                    FromStr::from_str(url).map_err(|e| format!("Invalid EL URL {url}: {e:?}"))?;
+                let api =
+                    HttpJsonRpc::new_with_auth(parsed_url, auth, execution_timeout_multiplier)
+                        .map_err(|e| format!("Error connecting to EL {url}: {e:?}"))?;
+
+                Ok(UpstreamEngine {
+                    url: url.clone(),
+                    engine: Engine::new(api, executor.clone(), log),
+                    chain_id: Mutex::new(None),
+                    healthy: AtomicBool::new(true),
+                    status: Mutex::new(HealthStatus::default()),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        if engines.is_empty() {
+            return Err("at least one EL URL is required".into());
+        }
+
+        Ok(Self {
+            engines,
+            active: AtomicUsize::new(0),
+            replay: Mutex::new(ReplayBuffer::default()),
+        })
+    }
+
+    /// The engine that requests should currently be sent to.
+    pub fn active(&self) -> &Engine {
+        &self.engines[self.active.load(Ordering::Relaxed)].engine
+    }
+
+    /// Index of the active engine in `--ee-url`.
+    pub fn active_index(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn has_backups(&self) -> bool {
+        self.engines.len() > 1
+    }
+
+    /// Check the chain ID of each engine at startup, and activate the first whose chain ID matches
+    /// the network.
+    ///
+    /// Engines are retried until one of them responds, so that eleel can start while the primary
+    /// engine is down. Engines other than the active one are marked unhealthy until they are
+    /// checked by the health monitor.
+    pub async fn select_initial(&self, spec: &ChainSpec) -> Result<u64, String> {
+        let timeout = Duration::from_secs(CHAIN_ID_TIMEOUT_SECS);
+        let mut attempt = 1;
+        loop {
+            let mut mismatches = 0;
+            for (i, upstream) in self.engines.iter().enumerate() {
+                let result = upstream
+                    .engine
+                    .api
+                    .get_chain_id(timeout)
+                    .await
+                    .map(u64::from)
+                    .map_err(|e| format!("unable to fetch chain ID: {e:?}"));
+                let error = match result {
+                    Ok(chain_id) if chain_id == spec.deposit_chain_id => {
+                        tracing::info!(url = upstream.url, chain_id, "EL chain ID matches network");
+                        upstream.set_chain_id(chain_id);
+                        upstream.set_health(Ok(()));
+                        self.active.store(i, Ordering::Relaxed);
+                        for other in &self.engines[i + 1..] {
+                            other.set_health(Err("not checked at startup".into()));
+                        }
+                        return Ok(chain_id);
+                    }
+                    Ok(chain_id) => {
+                        mismatches += 1;
+                        format!(
+                            "chain ID {chain_id} does not match network chain ID {}",
+                            spec.deposit_chain_id
+                        )
+                    }
+                    Err(e) => e,
+                };
+                tracing::warn!(
+                    url = upstream.url,
+                    attempt,
+                    error,
+                    "EL failed chain ID check"
+                );
+                upstream.set_health(Err(error));
+            }
+
+            if mismatches == self.engines.len() {
+                return Err("no EL has a chain ID matching the network".into());
+            }
+            if attempt == CHAIN_ID_ATTEMPTS {
+                return Err(format!(
+                    "unable to fetch chain ID from any EL after {attempt} attempts"
+                ));
+            }
+            attempt += 1;
+            tokio::time::sleep(Duration::from_secs(CHAIN_ID_RETRY_DELAY_SECS)).await;
+        }
+    }
+
+    pub fn status(&self) -> Vec<EngineStatus> {
+        let active = self.active.load(Ordering::Relaxed);
+        self.engines
+            .iter()
+            .enumerate()
+            .map(|(i, upstream)| {
+                let status = upstream.status.lock().unwrap();
+                EngineStatus {
+                    url: upstream.url.clone(),
+                    active: i == active,
+                    healthy: upstream.healthy.load(Ordering::Relaxed),
+                    chain_id: *upstream.chain_id.lock().unwrap(),
+                    last_checked: status
+                        .last_checked
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                        .map(|d| d.as_secs()),
+                    last_error: status.last_error.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Remember a newPayload sent by the controller, for replay on failover.
+    pub fn record_new_payload(&self, method: String, params: JsonValue) {
+        let mut replay = self.replay.lock().unwrap();
+        if replay.new_payloads.len() == REPLAY_NEW_PAYLOADS {
+            replay.new_payloads.pop_front();
+        }
+        replay.new_payloads.push_back((method, params));
+    }
+
+    /// Remember the latest fcU sent by the controller, for replay on failover.
+    pub fn record_fcu(&self, method: String, params: JsonValue) {
+        self.replay.lock().unwrap().fcu = Some((method, params));
+    }
+}
+
+impl UpstreamEngine {
+    pub fn set_chain_id(&self, chain_id: u64) {
+        *self.chain_id.lock().unwrap() = Some(chain_id);
+    }
+
+    fn set_health(&self, result: Result<(), String>) {
+        let healthy = result.is_ok();
+        let was_healthy = self.healthy.swap(healthy, Ordering::Relaxed);
+        match &result {
+            Err(e) if was_healthy => tracing::warn!(url = self.url, error = e, "EL unhealthy"),
+            Ok(()) if !was_healthy => tracing::info!(url = self.url, "EL healthy"),
+            _ => (),
+        }
+        metrics::set_engine_healthy(&self.url, healthy);
+
+        let mut status = self.status.lock().unwrap();
+        status.last_checked = Some(SystemTime::now());
+        status.last_error = result.err();
+    }
+}
+
+impl<E: EthSpec> Multiplexer<E> {
+    /// The engine that requests should currently be sent to.
+    pub fn engine(&self) -> &Engine {
+        self.engines.active()
+    }
+
+    /// Periodically check the health of every engine, failing over if necessary. Runs forever.
+    pub async fn monitor_engines(&self) {
+        let interval = Duration::from_secs(self.config.ee_health_check_secs);
+        loop {
+            tokio::time::sleep(interval).await;
+            self.check_engines().await;
+        }
+    }
+
+    async fn check_engines(&self) {
+        for upstream in &self.engines.engines {
+            let result = self.check_engine(upstream).await;
+            upstream.set_health(result);
+        }
+
+        let active = self.engines.active.load(Ordering::Relaxed);
+        let Some(preferred) = self
+            .engines
+            .engines
+            .iter()
+            .position(|upstream| upstream.healthy.load(Ordering::Relaxed))
+        else {
+            tracing::error!("no healthy ELs available");
+            return;
+        };
+        if preferred == active {
+            return;
+        }
+
+        let from = &self.engines.engines[active].url;
+        let to = &self.engines.engines[preferred];
+        tracing::warn!(from, to = to.url, "switching active EL");
+        self.replay_to(to).await;
+        self.engines.active.store(preferred, Ordering::Relaxed);
+        tracing::info!(url = to.url, "switched active EL");
+    }
+
+    /// Check that an engine is online, synced and on the right chain.
+    async fn check_engine(&self, upstream: &UpstreamEngine) -> Result<(), String> {
+        let chain_id = *upstream.chain_id.lock().unwrap();
+        if chain_id.is_none() {
+            let timeout = Duration::from_secs(self.config.ee_health_check_secs);
+            let chain_id = upstream
+                .engine
+                .api
+                .get_chain_id(timeout)
+                .await
+                .map(u64::from)
+                .map_err(|e| format!("unable to fetch chain ID: {e:?}"))?;
+            if chain_id != self.spec.deposit_chain_id {
+                return Err(format!(
+                    "chain ID {chain_id} does not match network chain ID {}",
+                    self.spec.deposit_chain_id
+                ));
+            }
+            upstream.set_chain_id(chain_id);
+        }
+
+        upstream
+            .engine
+            .api
+            .upcheck()
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    /// Send recent controller payloads and the latest fcU to an engine we are switching to.
+    ///
+    /// Errors are logged but otherwise ignored: the engine is still a better choice than an
+    /// unhealthy one, and will catch up once the controller sends its next messages.
+    async fn replay_to(&self, upstream: &UpstreamEngine) {
+        let (new_payloads, fcu) = {
+            let replay = self.engines.replay.lock().unwrap();
+            (replay.new_payloads.clone(), replay.fcu.clone())
+        };
+        tracing::info!(
+            url = upstream.url,
+            new_payloads = new_payloads.len(),
+            fcu = fcu.is_some(),
+            "replaying controller messages to EL"
+        );
+
+        let requests = new_payloads
+            .into_iter()
+            .map(|request| (request, ENGINE_NEW_PAYLOAD_TIMEOUT))
+            .chain(fcu.map(|request| (request, ENGINE_FORKCHOICE_UPDATED_TIMEOUT)));
+        for ((method, params), timeout) in requests {
+            if let Err(e) = upstream
+                .engine
+                .api
+                .rpc_request::<JsonValue>(&method, params, timeout)
+                .await
+            {
+                tracing::warn!(url = upstream.url, method, error = ?e, "replay failed");
+            }
+        }
+    }
+}
@@ -1,16 +1,24 @@
 //! Handler for forkchoiceUpdated.
 use crate::{
     config::FcuMatching,
+    metrics::{self, ResponseKind},
     multiplexer::Multiplexer,
+    scheduler::Priority,
     types::{
         ErrorResponse, JsonForkchoiceStateV1, JsonForkchoiceUpdatedV1Response,
-        JsonPayloadAttributes, JsonPayloadAttributesV2, JsonPayloadStatusV1,
-        JsonPayloadStatusV1Status, JsonValue, Request, Response, TransparentJsonPayloadId,
+        JsonPayloadAttributes, JsonPayloadStatusV1, JsonPayloadStatusV1Status, JsonValue, Request,
+        Response, TransparentJsonPayloadId,
     },
+    waiters::wait_until,
 };
 use eth2::types::EthSpec;
-use execution_layer::http::ENGINE_FORKCHOICE_UPDATED_V2;
-use std::time::{Duration, Instant};
+use execution_layer::{
+    engine_api::Error as EngineApiError,
+    http::{ENGINE_FORKCHOICE_UPDATED_V1, ENGINE_FORKCHOICE_UPDATED_V2},
+    PayloadAttributes,
+};
+use std::time::Duration;
+use tokio::time::Instant;
 
 impl<E: EthSpec> Multiplexer<E> {
     pub async fn handle_controller_fcu(&self, request: Request) -> Result<Response, ErrorResponse> {
@@ -21,43 +29,37 @@ impl<E: EthSpec> Multiplexer<E> {
 
         let head_hash = fcu.head_block_hash;
         tracing::info!(head_hash = ?head_hash, "processing fcU from controller");
+        *self.last_controller_fcu.lock().await = Some(Instant::now());
 
-        let opt_payload_attributes = if method_name == ENGINE_FORKCHOICE_UPDATED_V2 {
-            json_payload_attributes
-                .map(serde_json::from_value)
-                .transpose()
-                .map_err(|e| {
-                    ErrorResponse::parse_error_generic(
-                        id.clone(),
-                        format!("invalid payload attributes: {e}"),
-                    )
-                })?
-                .map(JsonPayloadAttributes::V2)
-        } else {
-            json_payload_attributes
-                .map(serde_json::from_value)
-                .transpose()
-                .map_err(|e| {
-                    ErrorResponse::parse_error_generic(
-                        id.clone(),
-                        format!("invalid payload attributes: {e}"),
-                    )
-                })?
-                .map(JsonPayloadAttributes::V3)
-        };
+        let opt_payload_attributes =
+            Self::decode_payload_attributes(&method_name, &id, json_payload_attributes)?;
 
-        let payload_status = if let Some(status) = self.get_cached_fcu(&fcu, true).await {
+        // When forwarding payload attributes we need a payload ID from the EL, so we can't
+        // short-circuit using the cache.
+        let forward_attributes =
+            self.config.forward_payload_attributes && opt_payload_attributes.is_some();
+        let mut el_payload_id = None;
+
+        let cached_status = self.get_cached_fcu(&fcu, true).await;
+        let payload_status = if let Some(status) = cached_status.filter(|_| !forward_attributes) {
             status
         } else {
             // Make a corresponding request to the EL.
-            // Do not send payload attributes to the EL (for now).
+            // Only send payload attributes to the EL if forwarding is enabled.
+            let el_payload_attributes = opt_payload_attributes
+                .clone()
+                .filter(|_| forward_attributes)
+                .map(Into::into);
             match self
-                .engine
-                .notify_forkchoice_updated(fcu.clone().into(), None, &self.log)
+                .el_forkchoice_updated(&method_name, &fcu, el_payload_attributes)
                 .await
             {
-                Ok(response) => {
-                    let json_response = JsonForkchoiceUpdatedV1Response::from(response);
+                Ok(json_response) => {
+                    el_payload_id = json_response.payload_id;
+                    if self.engines.has_backups() {
+                        let params = serde_json::json!([fcu, JsonValue::Null]);
+                        self.engines.record_fcu(method_name.clone(), params);
+                    }
                     let status = json_response.payload_status.status;
 
                     let mut cache = self.fcu_cache.lock().await;
@@ -87,6 +89,11 @@ impl<E: EthSpec> Multiplexer<E> {
                         );
 
                         if status == JsonPayloadStatusV1Status::Valid {
+                            let mut block_tree = self.block_tree.lock().await;
+                            block_tree.mark_valid(head_hash);
+                            block_tree.prune(fcu.finalized_block_hash);
+                            drop(block_tree);
+
                             self.justified_block_cache
                                 .lock()
                                 .await
@@ -96,6 +103,11 @@ impl<E: EthSpec> Multiplexer<E> {
                                 .await
                                 .put(fcu.finalized_block_hash, ());
                         }
+
+                        // Wake clients waiting on this head. This must happen after the block tree
+                        // and the justified and finalized caches are updated so that loose matching
+                        // succeeds.
+                        self.fcu_waiters.notify(&head_hash);
                     }
 
                     json_response.payload_status
@@ -122,13 +134,31 @@ impl<E: EthSpec> Multiplexer<E> {
                 head_hash = ?head_hash,
                 "processing payload attributes from controller"
             );
-            match self
+            let result = self
                 .register_attributes(head_hash, payload_attributes.into())
-                .await
-            {
-                Ok(id) => Some(TransparentJsonPayloadId(id)),
-                Err(message) => return Err(ErrorResponse::invalid_payload_attributes(id, message)),
+                .await;
+            let payload_id = match (result, el_payload_id) {
+                (Ok(payload_id), _) => payload_id,
+                // The EL is building a payload, so serve that even though we can't build a dummy
+                // payload, e.g. because the parent is unknown after a restart.
+                (Err(message), Some(_)) => {
+                    tracing::warn!(
+                        head_hash = ?head_hash,
+                        error = message,
+                        "unable to build dummy payload, relying on EL payload"
+                    );
+                    self.allocate_payload_id().await
+                }
+                (Err(message), None) => {
+                    return Err(ErrorResponse::invalid_payload_attributes(id, message))
+                }
+            };
+            // Map our payload ID to the EL's so that the real payload can be served.
+            if let Some(el_payload_id) = el_payload_id {
+                self.register_el_payload_id(payload_id, el_payload_id.into())
+                    .await;
             }
+            Some(TransparentJsonPayloadId(payload_id))
         } else {
             None
         };
@@ -141,9 +171,53 @@ impl<E: EthSpec> Multiplexer<E> {
         Response::new(id, response)
     }
 
+    /// Send an fcU to the EL, optionally with payload attributes.
+    ///
+    /// If the EL rejects the fcU with payload attributes, then the fcU is retried without them so
+    /// that the canonical chain is unaffected. The dummy payload builder is used in this case.
+    /// Timeouts and connection errors are not retried, as the EL is unlikely to fare any better
+    /// without the payload attributes.
+    async fn el_forkchoice_updated(
+        &self,
+        method: &str,
+        fcu: &JsonForkchoiceStateV1,
+        payload_attributes: Option<PayloadAttributes>,
+    ) -> Result<JsonForkchoiceUpdatedV1Response, String> {
+        let had_payload_attributes = payload_attributes.is_some();
+        let _permit = self.scheduler.acquire(Priority::Controller).await?;
+        let timer = metrics::upstream_timer(method);
+        let result = self
+            .engine()
+            .notify_forkchoice_updated(fcu.clone().into(), payload_attributes, &self.log)
+            .await;
+        drop(timer);
+
+        match result {
+            Ok(response) => Ok(JsonForkchoiceUpdatedV1Response::from(response)),
+            Err(EngineApiError::ServerMessage { code, message }) if had_payload_attributes => {
+                tracing::warn!(
+                    code,
+                    message,
+                    head_hash = ?fcu.head_block_hash,
+                    "EL rejected payload attributes, retrying fcU without them"
+                );
+                let _timer = metrics::upstream_timer(method);
+                self.engine()
+                    .notify_forkchoice_updated(fcu.clone().into(), None, &self.log)
+                    .await
+                    .map(JsonForkchoiceUpdatedV1Response::from)
+            }
+            Err(e) => Err(e),
+        }
+        .map_err(|e| format!("{e:?}"))
+    }
+
     pub async fn handle_fcu(&self, request: Request) -> Result<Response, ErrorResponse> {
-        let (id, (fcu, opt_payload_attributes)) =
-            request.parse_as::<(JsonForkchoiceStateV1, Option<JsonPayloadAttributesV2>)>()?;
+        let method = request.method.clone();
+        let (id, (fcu, json_payload_attributes)) =
+            request.parse_as::<(JsonForkchoiceStateV1, Option<JsonValue>)>()?;
+        let opt_payload_attributes =
+            Self::decode_payload_attributes(&method, &id, json_payload_attributes)?;
 
         let head_hash = fcu.head_block_hash;
         tracing::info!(id = ?id, head_hash = ?head_hash, "processing fcU from client");
@@ -152,28 +226,37 @@ impl<E: EthSpec> Multiplexer<E> {
         // the fcU sent by the controlling BN.
         let mut definite_payload_status = None;
         let start = Instant::now();
-        while start.elapsed().as_millis() < self.config.fcu_wait_millis {
+        let deadline = start + Duration::from_millis(self.config.fcu_wait_millis);
+        let mut rx = self.fcu_waiters.subscribe(head_hash);
+        loop {
             if let Some(definite_status) = self.get_cached_fcu(&fcu, true).await {
                 tracing::debug!(id = ?id, head_hash = ?head_hash, "found definite fcU in cache");
                 definite_payload_status = Some(definite_status);
                 break;
             }
-            tokio::time::sleep(Duration::from_millis(50)).await;
+            if !wait_until(&mut rx, deadline).await {
+                break;
+            }
         }
+        metrics::observe_wait_duration(&method, start.elapsed());
 
         // Check cache, allowing for indefinite Syncing/Accepted responses.
         let payload_status = if let Some(definite_status) = definite_payload_status {
+            metrics::inc_response(&method, ResponseKind::CacheHitDefinite);
             definite_status
         } else if let Some(payload_status) = self.get_cached_fcu(&fcu, false).await {
             if Self::is_definite(&payload_status) {
                 tracing::debug!(id = ?id, head_hash = ?head_hash, "found definite fcU in cache");
+                metrics::inc_response(&method, ResponseKind::CacheHitDefinite);
             } else {
                 tracing::info!("sending cached indefinite status on fcU");
+                metrics::inc_response(&method, ResponseKind::CacheHitIndefinite);
             }
             payload_status
         } else {
             // Synthesise a syncing response to send, but do not cache it.
             tracing::info!(id = ?id, head_hash = ?head_hash, "sending SYNCING status on fcU");
+            metrics::inc_response(&method, ResponseKind::SyntheticSyncing);
             JsonPayloadStatusV1 {
                 status: JsonPayloadStatusV1Status::Syncing,
                 latest_valid_hash: None,
@@ -181,23 +264,34 @@ impl<E: EthSpec> Multiplexer<E> {
             }
         };
 
-        // FIXME: wait for payload attributes from controller?
-        let payload_id = if let Some(payload_attributes) = opt_payload_attributes {
-            match self
-                .get_existing_payload_id(
-                    head_hash,
-                    JsonPayloadAttributes::V2(payload_attributes).into(),
-                )
-                .await
+        // Register the client's payload attributes with the dummy payload builder. Payloads are
+        // only built atop VALID heads, which are known to the builder from the controller's
+        // newPayload messages.
+        let payload_id = match opt_payload_attributes {
+            Some(payload_attributes)
+                if payload_status.status == JsonPayloadStatusV1Status::Valid =>
             {
-                Ok(payload_id) => Some(TransparentJsonPayloadId(payload_id)),
-                Err(message) => {
-                    tracing::warn!(message, "unable to build payload for client");
-                    None
+                match self
+                    .register_attributes(head_hash, payload_attributes.into())
+                    .await
+                {
+                    Ok(payload_id) => Some(TransparentJsonPayloadId(payload_id)),
+                    Err(message) => {
+                        tracing::warn!(message, "unable to build payload for client");
+                        None
+                    }
                 }
             }
-        } else {
-            None
+            Some(_) => {
+                tracing::info!(
+                    id = ?id,
+                    head_hash = ?head_hash,
+                    status = ?payload_status.status,
+                    "not building payload for client on non-VALID head"
+                );
+                None
+            }
+            None => None,
         };
 
         let response = JsonForkchoiceUpdatedV1Response {
@@ -208,6 +302,33 @@ impl<E: EthSpec> Multiplexer<E> {
         Response::new(id, response)
     }
 
+    /// Decode payload attributes according to the version of the fcU method.
+    fn decode_payload_attributes(
+        method: &str,
+        id: &JsonValue,
+        json_payload_attributes: Option<JsonValue>,
+    ) -> Result<Option<JsonPayloadAttributes>, ErrorResponse> {
+        let Some(json_payload_attributes) = json_payload_attributes else {
+            return Ok(None);
+        };
+        let payload_attributes = match method {
+            ENGINE_FORKCHOICE_UPDATED_V1 => {
+                serde_json::from_value(json_payload_attributes).map(JsonPayloadAttributes::V1)
+            }
+            ENGINE_FORKCHOICE_UPDATED_V2 => {
+                serde_json::from_value(json_payload_attributes).map(JsonPayloadAttributes::V2)
+            }
+            _ => serde_json::from_value(json_payload_attributes).map(JsonPayloadAttributes::V3),
+        }
+        .map_err(|e| {
+            ErrorResponse::parse_error_generic(
+                id.clone(),
+                format!("invalid payload attributes: {e}"),
+            )
+        })?;
+        Ok(Some(payload_attributes))
+    }
+
     /// Get fcU from cache.
     ///
     /// Definite (valid/invalid) responses may be requested by setting `definite_only=true`.
@@ -229,15 +350,27 @@ impl<E: EthSpec> Multiplexer<E> {
         let just_and_fin_ok = match self.config.fcu_matching {
             FcuMatching::Exact | FcuMatching::HeadOnly => true,
             FcuMatching::Loose => {
-                self.justified_block_cache
-                    .lock()
-                    .await
-                    .contains(&fcu.safe_block_hash)
-                    && self
-                        .finalized_block_cache
+                // Prefer the block tree, which knows the ancestry of the head. Fall back on the
+                // justified and finalized caches, which cover blocks the tree has not seen (e.g.
+                // after a restart).
+                let block_tree = self.block_tree.lock().await;
+                let is_known_descendant = block_tree
+                    .is_valid_descendant(fcu.head_block_hash, fcu.safe_block_hash)
+                    && block_tree
+                        .is_valid_descendant(fcu.safe_block_hash, fcu.finalized_block_hash);
+                drop(block_tree);
+
+                is_known_descendant
+                    || (self
+                        .justified_block_cache
                         .lock()
                         .await
-                        .contains(&fcu.finalized_block_hash)
+                        .contains(&fcu.safe_block_hash)
+                        && self
+                            .finalized_block_cache
+                            .lock()
+                            .await
+                            .contains(&fcu.finalized_block_hash))
             }
         };
 
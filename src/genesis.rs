@@ -0,0 +1,99 @@
+//! Determination of the genesis time, ideally without downloading the genesis state.
+use crate::config::Config;
+use eth2::types::{ChainSpec, EthSpec};
+use eth2_network_config::Eth2NetworkConfig;
+use slog::Logger;
+use std::time::Duration;
+
+const GENESIS_STATE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Work out the genesis time using the cheapest method available.
+///
+/// In order of preference:
+///
+/// 1. `--genesis-time` provided explicitly.
+/// 2. `--genesis-state` SSZ file on disk.
+/// 3. `MIN_GENESIS_TIME + GENESIS_DELAY` from the network config (if enabled).
+/// 4. Genesis state bytes bundled with the network config.
+/// 5. Genesis state downloaded from a URL (slow, requires network access).
+pub async fn genesis_time<E: EthSpec>(
+    config: &Config,
+    network_config: &Eth2NetworkConfig,
+    spec: &ChainSpec,
+    log: &Logger,
+) -> Result<u64, String> {
+    if let Some(genesis_time) = config.genesis_time {
+        tracing::info!(genesis_time, "using genesis time from CLI");
+        return Ok(genesis_time);
+    }
+
+    if let Some(path) = &config.genesis_state {
+        let bytes = std::fs::read(path)
+            .map_err(|e| format!("Unable to read genesis state {}: {e}", path.display()))?;
+        let genesis_time = genesis_time_from_ssz(&bytes)?;
+        tracing::info!(genesis_time, path = %path.display(), "read genesis time from file");
+        return Ok(genesis_time);
+    }
+
+    if config.genesis_time_from_config {
+        let genesis_time = spec
+            .min_genesis_time
+            .checked_add(spec.genesis_delay)
+            .ok_or("genesis time overflow")?;
+        tracing::info!(genesis_time, "computed genesis time from network config");
+        return Ok(genesis_time);
+    }
+
+    if let Some(bytes) = &network_config.genesis_state_bytes {
+        let genesis_time = genesis_time_from_ssz(&bytes[..])?;
+        tracing::info!(genesis_time, "read genesis time from bundled genesis state");
+        return Ok(genesis_time);
+    }
+
+    tracing::info!("downloading genesis state to determine genesis time");
+    let genesis_state = network_config
+        .genesis_state::<E>(
+            config.genesis_state_url.as_deref(),
+            GENESIS_STATE_TIMEOUT,
+            log,
+        )
+        .await
+        .map_err(|e| format!("Unable to download genesis state: {e}"))?
+        .ok_or(
+            "Unable to determine genesis time: no genesis state is known for this network. \
+             Provide one of --genesis-time, --genesis-state, --genesis-state-url or \
+             --genesis-time-from-config",
+        )?;
+    Ok(genesis_state.genesis_time())
+}
+
+/// Read the genesis time from an SSZ-encoded `BeaconState` without decoding the whole state.
+///
+/// The `genesis_time` is the first field of the state in every fork, and is fixed-length.
+fn genesis_time_from_ssz(bytes: &[u8]) -> Result<u64, String> {
+    bytes
+        .get(..8)
+        .and_then(|slice| slice.try_into().ok())
+        .map(u64::from_le_bytes)
+        .ok_or_else(|| format!("Genesis state too short: {} bytes", bytes.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn genesis_time_from_state_prefix() {
+        let genesis_time = 1_606_824_023u64;
+        let mut bytes = genesis_time.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0xff; 32]);
+        assert_eq!(genesis_time_from_ssz(&bytes), Ok(genesis_time));
+        assert_eq!(genesis_time_from_ssz(&bytes[..8]), Ok(genesis_time));
+    }
+
+    #[test]
+    fn genesis_state_too_short() {
+        assert!(genesis_time_from_ssz(&[]).is_err());
+        assert!(genesis_time_from_ssz(&[0; 7]).is_err());
+    }
+}
@@ -1,11 +1,20 @@
 //! JWT authentication supporting multiple secrets identified by ID.
-use crate::config::ClientJwtSecrets;
+use crate::config::{ClientJwtSecrets, ClientSettings};
+use crate::controllers::Controllers;
+use crate::rate_limit::{Limits, MethodClass};
+#[cfg(unix)]
+use arc_swap::ArcSwap;
 use hmac::{Hmac, Mac};
 use jwt::{Error, Header, Token, Unverified, Verified, VerifyWithKey};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
+#[cfg(unix)]
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::path::Path;
+#[cfg(unix)]
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub type VerifiedToken = Token<Header, Claims, Verified>;
 pub type UnverifiedToken<'a> = Token<Header, Claims, Unverified<'a>>;
@@ -14,6 +23,8 @@ pub type Secret = Hmac<Sha256>;
 /// Collection of JWT secrets organised by ID, allowing for each client to use its own secret.
 pub struct KeyCollection {
     secrets: HashMap<String, Secret>,
+    settings: HashMap<String, ClientSettings>,
+    default_limits: HashMap<MethodClass, Limits>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -26,6 +37,32 @@ pub struct Claims {
     clv: Option<String>,
 }
 
+impl Claims {
+    /// Client version of the CL node, if provided.
+    pub fn clv(&self) -> Option<&str> {
+        self.clv.as_deref()
+    }
+
+    /// Check that the token was issued within `tolerance` of the current time.
+    ///
+    /// The engine API spec requires a tolerance of +-60 seconds, which prevents the replay of
+    /// captured tokens.
+    pub fn check_iat(&self, tolerance: Duration) -> Result<(), String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| format!("System time error: {e}"))?
+            .as_secs();
+        let drift = now.abs_diff(self.iat);
+        if drift > tolerance.as_secs() {
+            return Err(format!(
+                "JWT iat {} differs from current time {now} by {drift}s",
+                self.iat
+            ));
+        }
+        Ok(())
+    }
+}
+
 pub fn verify_single_token(token: &str, secret: &Secret) -> Result<VerifiedToken, String> {
     token.verify_with_key(secret).map_err(convert_err)
 }
@@ -43,7 +80,8 @@ pub fn jwt_secret_from_path(path: &Path) -> Result<Secret, String> {
 }
 
 impl KeyCollection {
-    pub fn verify(&self, token: &str) -> Result<VerifiedToken, String> {
+    /// Verify a token against the collection, returning the ID of the matching key.
+    pub fn verify(&self, token: &str) -> Result<(String, VerifiedToken), String> {
         let parsed_token = UnverifiedToken::parse_unverified(token).map_err(convert_err)?;
 
         // Look up the key by ID. Unlike other JWT implementations, the engine API puts the key ID
@@ -56,7 +94,10 @@ impl KeyCollection {
 
         if let Some((id, secret)) = secret {
             tracing::trace!(id = id, "matched JWT secret by ID");
-            return verify_parsed_token(parsed_token, secret);
+            let id = id.clone();
+            let token = verify_parsed_token(parsed_token, secret)?;
+            self.check_enabled(&id)?;
+            return Ok((id, token));
         }
 
         // Otherwise try every token available (slow).
@@ -64,7 +105,8 @@ impl KeyCollection {
         for (id, secret) in &self.secrets {
             if let Ok(token) = verify_single_token(token, secret) {
                 tracing::trace!(id = id, "matched JWT secret by iteration");
-                return Ok(token);
+                self.check_enabled(id)?;
+                return Ok((id.clone(), token));
             }
         }
 
@@ -72,12 +114,44 @@ impl KeyCollection {
         Err("No matching JWT secret found".into())
     }
 
+    fn check_enabled(&self, id: &str) -> Result<(), String> {
+        if self
+            .settings
+            .get(id)
+            .is_some_and(|settings| settings.disabled)
+        {
+            return Err(format!("Client {id} is disabled"));
+        }
+        Ok(())
+    }
+
+    /// Get the limits that apply to the client with the given key ID for a class of methods.
+    pub fn limits(&self, id: &str, class: MethodClass) -> Option<&Limits> {
+        self.settings
+            .get(id)
+            .and_then(|settings| settings.limits.get(&class))
+            .or_else(|| self.default_limits.get(&class))
+    }
+
+    /// Check whether the client with the given key ID may call `method`.
+    pub fn is_method_allowed(&self, id: &str, method: &str) -> bool {
+        self.settings
+            .get(id)
+            .is_none_or(|settings| settings.is_method_allowed(method))
+    }
+
     pub fn load(path: &Path) -> Result<Self, String> {
-        let raw = ClientJwtSecrets::from_file(path)?;
+        Self::from_secrets(ClientJwtSecrets::from_file(path)?)
+    }
 
+    fn from_secrets(raw: ClientJwtSecrets) -> Result<Self, String> {
         let mut secrets = HashMap::with_capacity(raw.secrets.len());
 
         for (id, hex_secret) in raw.secrets {
+            // Clients share a namespace with controllers in the client registry and metrics.
+            if Controllers::is_reserved_name(&id) {
+                return Err(format!("Client ID {id} is reserved for controllers"));
+            }
             let byte_secret =
                 hex::decode(&hex_secret).map_err(|e| format!("Invalid JWT secret: {e:?}"))?;
 
@@ -86,10 +160,191 @@ impl KeyCollection {
             secrets.insert(id, secret);
         }
 
-        Ok(Self { secrets })
+        // Catch typos in client IDs, which would otherwise silently grant full access.
+        if let Some(id) = raw.clients.keys().find(|id| !secrets.contains_key(*id)) {
+            return Err(format!("Settings provided for unknown client ID: {id}"));
+        }
+
+        for (class, limits) in &raw.default_limits {
+            let class_name: &str = (*class).into();
+            limits
+                .validate()
+                .map_err(|e| format!("Invalid default {class_name} limits: {e}"))?;
+        }
+        for (id, settings) in &raw.clients {
+            for (class, limits) in &settings.limits {
+                let class_name: &str = (*class).into();
+                limits
+                    .validate()
+                    .map_err(|e| format!("Invalid {class_name} limits for client {id}: {e}"))?;
+            }
+        }
+
+        Ok(Self {
+            secrets,
+            settings: raw.clients,
+            default_limits: raw.default_limits,
+        })
+    }
+
+    #[cfg(unix)]
+    pub fn key_ids(&self) -> BTreeSet<&str> {
+        self.secrets.keys().map(String::as_str).collect()
     }
 }
 
+/// Reload a key collection from disk, atomically replacing the current collection.
+///
+/// If the new secrets file is invalid then an error is returned and the current collection is
+/// left untouched.
+#[cfg(unix)]
+pub fn reload_key_collection(current: &ArcSwap<KeyCollection>, path: &Path) -> Result<(), String> {
+    let new = Arc::new(KeyCollection::load(path)?);
+    let old = current.swap(new.clone());
+
+    let old_ids = old.key_ids();
+    let new_ids = new.key_ids();
+    let added = new_ids.difference(&old_ids).collect::<Vec<_>>();
+    let removed = old_ids.difference(&new_ids).collect::<Vec<_>>();
+    tracing::info!(
+        ?added,
+        ?removed,
+        num_keys = new_ids.len(),
+        "reloaded client JWT secrets"
+    );
+    Ok(())
+}
+
 fn convert_err(e: Error) -> String {
     format!("JWT verification error: {e}")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+
+    fn key_collection(toml_str: &str) -> Result<KeyCollection, String> {
+        KeyCollection::from_secrets(toml::from_str(toml_str).map_err(|e| e.to_string())?)
+    }
+
+    fn claims_issued_at(iat: u64) -> Claims {
+        Claims {
+            iat,
+            id: None,
+            clv: None,
+        }
+    }
+
+    #[test]
+    fn iat_tolerance() {
+        let tolerance = Duration::from_secs(60);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        // Allow a little slack for the clock advancing during the test.
+        assert!(claims_issued_at(now).check_iat(tolerance).is_ok());
+        assert!(claims_issued_at(now - 55).check_iat(tolerance).is_ok());
+        assert!(claims_issued_at(now + 55).check_iat(tolerance).is_ok());
+
+        assert!(claims_issued_at(now - 120).check_iat(tolerance).is_err());
+        assert!(claims_issued_at(now + 120).check_iat(tolerance).is_err());
+        assert!(claims_issued_at(0).check_iat(tolerance).is_err());
+    }
+
+    #[test]
+    fn disabled_client_rejected() {
+        let keys = key_collection(&format!(
+            r#"
+            [secrets]
+            node1 = "{SECRET}"
+            node2 = "{SECRET}"
+
+            [clients.node1]
+            disabled = true
+            "#
+        ))
+        .unwrap();
+        assert!(keys.check_enabled("node1").is_err());
+        assert!(keys.check_enabled("node2").is_ok());
+    }
+
+    #[test]
+    fn settings_for_unknown_id_rejected() {
+        let result = key_collection(&format!(
+            r#"
+            [secrets]
+            node1 = "{SECRET}"
+
+            [clients.nod1]
+            role = "analytics"
+            "#
+        ));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn methods_allowed_by_settings() {
+        let keys = key_collection(&format!(
+            r#"
+            [secrets]
+            node1 = "{SECRET}"
+            node2 = "{SECRET}"
+
+            [clients.node1]
+            role = "engine-only"
+            "#
+        ))
+        .unwrap();
+        assert!(!keys.is_method_allowed("node1", "eth_call"));
+        assert!(keys.is_method_allowed("node2", "eth_call"));
+    }
+
+    #[test]
+    fn invalid_limits_rejected() {
+        let result = key_collection(&format!(
+            r#"
+            [secrets]
+            node1 = "{SECRET}"
+
+            [clients.node1.limits.heavy]
+            requests_per_second = 0.0
+            "#
+        ));
+        assert!(result.is_err());
+
+        let result = key_collection(&format!(
+            r#"
+            [secrets]
+            node1 = "{SECRET}"
+
+            [default_limits.proxy]
+            burst = 0
+            "#
+        ));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn controller_ids_rejected() {
+        for id in ["controller", "controller-1"] {
+            let result = key_collection(&format!(
+                r#"
+                [secrets]
+                {id} = "{SECRET}"
+                "#
+            ));
+            assert!(result.is_err(), "{id}");
+        }
+        let keys = key_collection(&format!(
+            r#"
+            [secrets]
+            controller-node = "{SECRET}"
+            "#
+        ));
+        assert!(keys.is_ok());
+    }
+}
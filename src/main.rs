@@ -1,11 +1,16 @@
 use crate::{
     config::Config,
-    jwt::{jwt_secret_from_path, verify_single_token, KeyCollection, Secret},
+    controllers::Controllers,
+    jwt::{jwt_secret_from_path, KeyCollection, VerifiedToken},
     multiplexer::Multiplexer,
+    rate_limit::{MethodClass, RateLimiter},
+    scheduler::Priority,
     types::{
-        ErrorResponse, MaybeErrorResponse, Request, Requests, Response, Responses, TaskExecutor,
+        ErrorCode, ErrorResponse, MaybeErrorResponse, Request, Requests, Response, Responses,
+        ShutdownReason, TaskExecutor,
     },
 };
+use arc_swap::ArcSwap;
 use axum::{
     extract::{rejection::JsonRejection, DefaultBodyLimit, State},
     headers::{authorization::Bearer, Authorization},
@@ -15,215 +20,767 @@ use axum::{
     Json, Router, TypedHeader,
 };
 use clap::Parser;
-use eth2::types::MainnetEthSpec;
+use eth2::types::{EthSpec, GnosisEthSpec, MainnetEthSpec, MinimalEthSpec};
+use eth2_network_config::Eth2NetworkConfig;
 use execution_layer::http::{
     ENGINE_EXCHANGE_CAPABILITIES, ENGINE_FORKCHOICE_UPDATED_V1, ENGINE_FORKCHOICE_UPDATED_V2,
     ENGINE_FORKCHOICE_UPDATED_V3, ENGINE_GET_CLIENT_VERSION_V1,
     ENGINE_GET_PAYLOAD_BODIES_BY_HASH_V1, ENGINE_GET_PAYLOAD_BODIES_BY_RANGE_V1,
     ENGINE_GET_PAYLOAD_V1, ENGINE_GET_PAYLOAD_V2, ENGINE_GET_PAYLOAD_V3, ENGINE_GET_PAYLOAD_V4,
-    ENGINE_NEW_PAYLOAD_V1, ENGINE_NEW_PAYLOAD_V2, ENGINE_NEW_PAYLOAD_V3, ENGINE_NEW_PAYLOAD_V4,
-    ETH_SYNCING,
+    ENGINE_GET_PAYLOAD_V5, ENGINE_NEW_PAYLOAD_V1, ENGINE_NEW_PAYLOAD_V2, ENGINE_NEW_PAYLOAD_V3,
+    ENGINE_NEW_PAYLOAD_V4, ETH_SYNCING,
 };
+use futures::channel::mpsc;
+use futures::stream::{self, StreamExt};
 use slog::Logger;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::runtime::Handle;
 
+mod admin;
 mod base_fee;
+mod block_tree;
+mod clients;
 mod config;
+mod controllers;
+mod engines;
 mod fcu;
+mod genesis;
 mod jwt;
 mod logging;
 mod meta;
+mod method_pattern;
+mod metrics;
 mod multiplexer;
 mod new_payload;
 mod payload_builder;
+mod persist;
+mod rate_limit;
+mod scheduler;
 mod types;
-
-// TODO: allow other specs
-type E = MainnetEthSpec;
+mod waiters;
 
 const MEGABYTE: usize = 1024 * 1024;
 
+/// Exit code after a clean shutdown.
+const EXIT_OK: i32 = 0;
+/// Exit code after an error, including failure to persist state on shutdown.
+const EXIT_ERROR: i32 = 1;
+/// Exit code if in-flight requests did not complete within `--shutdown-timeout-secs`.
+const EXIT_DRAIN_TIMEOUT: i32 = 2;
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
 
     let log = crate::logging::new_logger();
-    let executor = new_task_executor(log.clone()).await;
+    let (executor, shutdown) = new_task_executor(log.clone());
 
     let config = Config::parse();
+    let network_config = config.network_config().unwrap();
+
+    // Select the compile-time preset matching the network config.
+    let exit_code = match network_config.config.preset_base.as_str() {
+        "mainnet" => run::<MainnetEthSpec>(config, network_config, executor, shutdown, log).await,
+        "minimal" => run::<MinimalEthSpec>(config, network_config, executor, shutdown, log).await,
+        "gnosis" => run::<GnosisEthSpec>(config, network_config, executor, shutdown, log).await,
+        preset => panic!("unsupported preset: {preset}"),
+    };
+    std::process::exit(exit_code);
+}
 
+async fn run<E: EthSpec>(
+    config: Config,
+    network_config: Eth2NetworkConfig,
+    executor: TaskExecutor,
+    shutdown: Shutdown,
+    log: Logger,
+) -> i32 {
     let body_limit_mb = config.body_limit_mb;
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
     let listen_address = config.listen_address;
     let listen_port = config.listen_port;
-    let controller_jwt_secret = jwt_secret_from_path(&config.controller_jwt_secret).unwrap();
-    let client_jwt_collection = KeyCollection::load(&config.client_jwt_secrets).unwrap();
-    let multiplexer = Multiplexer::<E>::new(config, executor, log).await.unwrap();
+    let controller_jwt_secrets = config
+        .controller_jwt_secret
+        .iter()
+        .map(|path| jwt_secret_from_path(path))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let controllers = Controllers::new(
+        controller_jwt_secrets,
+        Duration::from_secs(config.controller_failover_secs),
+    );
+    let admin_jwt_secret = config
+        .admin_jwt_secret
+        .as_deref()
+        .map(jwt_secret_from_path)
+        .transpose()
+        .unwrap();
+    let jwt_iat_tolerance =
+        (!config.disable_jwt_iat_check).then(|| Duration::from_secs(config.jwt_iat_tolerance_secs));
+    let client_jwt_secrets_path = config.client_jwt_secrets.clone();
+    let client_jwt_collection =
+        ArcSwap::from_pointee(KeyCollection::load(&client_jwt_secrets_path).unwrap());
+    let multiplexer = Multiplexer::<E>::new(config, network_config, executor.clone(), log)
+        .await
+        .unwrap();
+    if let Err(e) = multiplexer.load_snapshot().await {
+        tracing::warn!(
+            error = e,
+            "unable to load snapshot, starting with empty caches"
+        );
+    }
     let app_state = Arc::new(AppState {
-        controller_jwt_secret,
+        controllers,
         client_jwt_collection,
+        jwt_iat_tolerance,
+        rate_limiter: RateLimiter::default(),
         multiplexer,
     });
 
-    let app = Router::new()
-        .route("/", post(handle_client_json_rpc))
-        .route("/canonical", post(handle_controller_json_rpc))
+    let monitor_state = app_state.clone();
+    tokio::spawn(async move { monitor_state.multiplexer.monitor_engines().await });
+
+    let persist_state = app_state.clone();
+    let persist_handle = tokio::spawn(async move {
+        persist_state
+            .multiplexer
+            .persist_periodically(executor)
+            .await
+    });
+
+    #[cfg(unix)]
+    tokio::spawn(reload_client_jwt_secrets_on_sighup(
+        app_state.clone(),
+        client_jwt_secrets_path,
+    ));
+
+    let mut app = Router::new()
+        .route("/", post(handle_client_json_rpc::<E>))
+        .route("/canonical", post(handle_controller_json_rpc::<E>))
         .route("/health", get(handle_health))
+        .route("/metrics", get(handle_metrics::<E>));
+    if let Some(secret) = admin_jwt_secret {
+        app = app.nest("/admin", admin::router(app_state.clone(), secret));
+    }
+    let app = app
         .with_state(app_state)
         .layer(DefaultBodyLimit::max(body_limit_mb * MEGABYTE));
 
     let addr = SocketAddr::from((listen_address, listen_port));
     tracing::debug!("listening on {}", addr);
-    axum::Server::bind(&addr)
+
+    // Stop accepting connections once a shutdown is requested, then give in-flight requests
+    // (including those waiting on the controller) a limited time to complete.
+    let Shutdown {
+        exit_signal,
+        shutdown_rx,
+    } = shutdown;
+    let (draining_tx, draining_rx) = tokio::sync::oneshot::channel();
+    let server = axum::Server::bind(&addr)
         .serve(app.into_make_service())
-        .await
-        .unwrap();
+        .with_graceful_shutdown(async move {
+            let reason = wait_for_shutdown(shutdown_rx).await;
+            tracing::info!(reason, "shutting down, waiting for in-flight requests");
+            let _ = draining_tx.send(());
+        });
+    let drain_timeout = async move {
+        match draining_rx.await {
+            Ok(()) => tokio::time::sleep(shutdown_timeout).await,
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    let mut exit_code = tokio::select! {
+        result = server => match result {
+            Ok(()) => EXIT_OK,
+            Err(e) => {
+                tracing::error!(error = ?e, "server error");
+                EXIT_ERROR
+            }
+        },
+        () = drain_timeout => {
+            tracing::warn!("timed out waiting for in-flight requests");
+            EXIT_DRAIN_TIMEOUT
+        }
+    };
+
+    // Fire the executor's exit signal by dropping the sender, which wakes every task waiting on
+    // it. Then wait for the final snapshot to be written.
+    drop(exit_signal);
+    match persist_handle.await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => {
+            tracing::error!(error = e, "failed to persist state");
+            exit_code = EXIT_ERROR;
+        }
+        Err(e) => {
+            tracing::error!(error = ?e, "persistence task failed");
+            exit_code = EXIT_ERROR;
+        }
+    }
+
+    tracing::info!(exit_code, "shutdown complete");
+    exit_code
 }
 
-struct AppState {
-    controller_jwt_secret: Secret,
-    client_jwt_collection: KeyCollection,
+/// Channels used to coordinate shutdown with the task executor.
+struct Shutdown {
+    /// Dropping this sender fires the executor's exit signal.
+    exit_signal: async_channel::Sender<()>,
+    /// Shutdown requests from tasks spawned on the executor.
+    shutdown_rx: mpsc::Receiver<ShutdownReason>,
+}
+
+/// Wait for SIGINT, SIGTERM (Unix only) or a shutdown request from a task, returning the reason.
+async fn wait_for_shutdown(mut shutdown_rx: mpsc::Receiver<ShutdownReason>) -> &'static str {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!(error = ?e, "unable to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        () = terminate => "SIGTERM",
+        Some(reason) = shutdown_rx.next() => reason.message(),
+    }
+}
+
+struct AppState<E: EthSpec> {
+    /// Primary and standby controllers.
+    controllers: Controllers,
+    /// Client secrets, which may be swapped out at runtime by a reload.
+    client_jwt_collection: ArcSwap<KeyCollection>,
+    /// Maximum drift between a token's `iat` and the current time, or `None` to skip the check.
+    jwt_iat_tolerance: Option<Duration>,
+    /// Rate limiting state for clients (the controller is exempt).
+    rate_limiter: RateLimiter,
     multiplexer: Multiplexer<E>,
 }
 
-// TODO: do something with signal/signal_rx
-async fn new_task_executor(log: Logger) -> TaskExecutor {
+impl<E: EthSpec> AppState<E> {
+    fn check_iat(&self, token: &VerifiedToken) -> Result<(), String> {
+        match self.jwt_iat_tolerance {
+            Some(tolerance) => token.claims().check_iat(tolerance),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reload the client JWT secrets from disk every time we receive a SIGHUP.
+#[cfg(unix)]
+async fn reload_client_jwt_secrets_on_sighup<E: EthSpec>(
+    state: Arc<AppState<E>>,
+    path: std::path::PathBuf,
+) {
+    use crate::jwt::reload_key_collection;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            tracing::warn!(error = ?e, "unable to listen for SIGHUP, secrets reload disabled");
+            return;
+        }
+    };
+
+    while sighup.recv().await.is_some() {
+        tracing::info!(path = %path.display(), "reloading client JWT secrets");
+        if let Err(e) = reload_key_collection(&state.client_jwt_collection, &path) {
+            tracing::error!(
+                error = e,
+                "failed to reload client JWT secrets, keeping old secrets"
+            );
+        }
+    }
+}
+
+fn new_task_executor(log: Logger) -> (TaskExecutor, Shutdown) {
     let handle = Handle::current();
-    let (_signal, exit) = async_channel::bounded(1);
-    let (shutdown_tx, _) = futures::channel::mpsc::channel(1);
-    TaskExecutor::new(handle, exit, log, shutdown_tx)
+    let (exit_signal, exit) = async_channel::bounded(1);
+    let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+    let executor = TaskExecutor::new(handle, exit, log, shutdown_tx);
+    let shutdown = Shutdown {
+        exit_signal,
+        shutdown_rx,
+    };
+    (executor, shutdown)
 }
 
-async fn handle_client_json_rpc(
-    State(state): State<Arc<AppState>>,
+async fn handle_client_json_rpc<E: EthSpec>(
+    State(state): State<Arc<AppState<E>>>,
     TypedHeader(jwt_token_str): TypedHeader<Authorization<Bearer>>,
     maybe_requests: Result<Json<Requests>, JsonRejection>,
-) -> Json<Responses> {
-    let jwt_key_collection = &state.client_jwt_collection;
-    let multiplexer = &state.multiplexer;
+) -> Result<Json<Responses>, (StatusCode, Json<Responses>)> {
+    let jwt_key_collection = state.client_jwt_collection.load_full();
 
     // Check JWT auth.
-    if let Err(e) = jwt_key_collection.verify(jwt_token_str.token()) {
+    let client_id = match jwt_key_collection
+        .verify(jwt_token_str.token())
+        .and_then(|(client_id, token)| state.check_iat(&token).map(|()| (client_id, token)))
+    {
+        Ok((client_id, token)) => {
+            let clv = token.claims().clv();
+            state.multiplexer.clients.record(&client_id, clv);
+            client_id
+        }
+        Err(e) => {
+            tracing::warn!(
+                error = ?e,
+                "JWT auth failed"
+            );
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                Json(Responses::Single(MaybeErrorResponse::Err(
+                    ErrorResponse::unauthorized(serde_json::json!(0), e),
+                ))),
+            ));
+        }
+    };
+
+    let requests = match maybe_requests {
+        Ok(Json(requests)) => requests,
+        Err(e) => {
+            return Ok(Json(Responses::Single(MaybeErrorResponse::Err(
+                ErrorResponse::parse_error_generic(serde_json::json!(0), e.body_text()),
+            ))));
+        }
+    };
+
+    let responses = match requests {
+        Requests::Single(request) => Responses::Single(
+            process_client_request(&state, &jwt_key_collection, &client_id, request)
+                .await
+                .into(),
+        ),
+        Requests::Multiple(requests) => {
+            let config = &state.multiplexer.config;
+            if let Err(e) = check_batch_size(&requests, config.max_batch_size) {
+                tracing::warn!(
+                    client_id,
+                    batch_size = requests.len(),
+                    "rejecting large batch"
+                );
+                return Ok(Json(Responses::Single(MaybeErrorResponse::Err(e))));
+            }
+
+            // Process requests concurrently. `buffered` yields results in the order of the
+            // requests, so responses line up with the batch.
+            let results = stream::iter(requests)
+                .map(|request| {
+                    process_client_request(&state, &jwt_key_collection, &client_id, request)
+                })
+                .buffered(config.batch_concurrency.max(1))
+                .map(MaybeErrorResponse::from)
+                .collect()
+                .await;
+
+            Responses::Multiple(results)
+        }
+    };
+    Ok(Json(responses))
+}
+
+fn check_batch_size(requests: &[Request], max_batch_size: usize) -> Result<(), ErrorResponse> {
+    if requests.len() > max_batch_size {
+        Err(ErrorResponse::too_large_request(
+            serde_json::json!(0),
+            format!(
+                "batch of {} requests exceeds the maximum of {max_batch_size}",
+                requests.len()
+            ),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+async fn process_client_request<E: EthSpec>(
+    state: &AppState<E>,
+    key_collection: &KeyCollection,
+    client_id: &str,
+    request: Request,
+) -> Result<Response, ErrorResponse> {
+    let config = &state.multiplexer.config;
+    let method = request.method.clone();
+    let start = Instant::now();
+
+    if !key_collection.is_method_allowed(client_id, &method) {
         tracing::warn!(
-            error = ?e,
-            "JWT auth failed"
+            client_id,
+            method,
+            "client attempted to call forbidden method"
         );
-        return Json(Responses::Single(MaybeErrorResponse::Err(
-            ErrorResponse::parse_error_generic(serde_json::json!(0), e),
-        )));
+        let result = Err(ErrorResponse::method_not_allowed(request.id, &method));
+        record_request_metrics(config, &method, client_id, start, &result);
+        return result;
     }
 
+    // Apply rate limits, holding the in-flight guard until the request is complete.
+    let method_class = MethodClass::of(&method);
+    let _in_flight = match key_collection.limits(client_id, method_class) {
+        Some(limits) => match state
+            .rate_limiter
+            .try_acquire(client_id, method_class, limits)
+        {
+            Ok(guard) => Some(guard),
+            Err(message) => {
+                tracing::debug!(client_id, method, message, "client exceeded limits");
+                let result = Err(ErrorResponse::limit_exceeded(request.id, message));
+                record_request_metrics(config, &method, client_id, start, &result);
+                return result;
+            }
+        },
+        None => None,
+    };
+
+    let result = dispatch(&state.multiplexer, Priority::Client, client_id, request).await;
+    record_request_metrics(config, &method, client_id, start, &result);
+    result
+}
+
+async fn handle_controller_json_rpc<E: EthSpec>(
+    State(state): State<Arc<AppState<E>>>,
+    TypedHeader(jwt_token_str): TypedHeader<Authorization<Bearer>>,
+    maybe_requests: Result<Json<Requests>, JsonRejection>,
+) -> Result<Json<Responses>, (StatusCode, Json<Responses>)> {
+    // Check JWT auth.
+    let (controller_index, token) = match state
+        .controllers
+        .verify(jwt_token_str.token())
+        .and_then(|(index, token)| state.check_iat(&token).map(|()| (index, token)))
+    {
+        Ok(verified) => verified,
+        Err(e) => {
+            tracing::warn!(
+                error = ?e,
+                "Controller JWT auth failed"
+            );
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                Json(Responses::Single(MaybeErrorResponse::Err(
+                    ErrorResponse::unauthorized(serde_json::json!(0), e),
+                ))),
+            ));
+        }
+    };
+    let controller_name = Controllers::name(controller_index);
+    let clv = token.claims().clv();
+    state.multiplexer.clients.record(&controller_name, clv);
+
+    // Standby controllers are treated like clients until they become active.
+    let priority = if state.controllers.record_request(controller_index) {
+        state
+            .multiplexer
+            .clients
+            .set_active_controller(&controller_name);
+        Priority::Controller
+    } else {
+        Priority::Client
+    };
+
     let requests = match maybe_requests {
         Ok(Json(requests)) => requests,
         Err(e) => {
-            return Json(Responses::Single(MaybeErrorResponse::Err(
+            return Ok(Json(Responses::Single(MaybeErrorResponse::Err(
                 ErrorResponse::parse_error_generic(serde_json::json!(0), e.body_text()),
-            )));
+            ))));
         }
     };
 
-    match requests {
-        Requests::Single(request) => Json(Responses::Single(
-            process_client_request(multiplexer, request).await.into(),
-        )),
+    let responses = match requests {
+        Requests::Single(request) => Responses::Single(
+            process_controller_request(&state, &controller_name, priority, request)
+                .await
+                .into(),
+        ),
         Requests::Multiple(requests) => {
-            let mut results = vec![];
+            if let Err(e) = check_batch_size(&requests, state.multiplexer.config.max_batch_size) {
+                tracing::warn!(
+                    batch_size = requests.len(),
+                    "rejecting large controller batch"
+                );
+                return Ok(Json(Responses::Single(MaybeErrorResponse::Err(e))));
+            }
 
+            // Process the controller's requests sequentially, as later requests may depend on
+            // earlier ones (e.g. an fcU to a payload sent by newPayload in the same batch).
+            let mut results = vec![];
             for request in requests {
-                results.push(process_client_request(multiplexer, request).await.into());
+                results.push(
+                    process_controller_request(&state, &controller_name, priority, request)
+                        .await
+                        .into(),
+                );
             }
-
-            Json(Responses::Multiple(results))
+            Responses::Multiple(results)
         }
-    }
+    };
+    Ok(Json(responses))
 }
 
-async fn process_client_request(
-    multiplexer: &Multiplexer<E>,
+async fn process_controller_request<E: EthSpec>(
+    state: &AppState<E>,
+    controller_name: &str,
+    priority: Priority,
     request: Request,
 ) -> Result<Response, ErrorResponse> {
-    match request.method.as_str() {
-        ENGINE_FORKCHOICE_UPDATED_V1
-        | ENGINE_FORKCHOICE_UPDATED_V2
-        | ENGINE_FORKCHOICE_UPDATED_V3 => multiplexer.handle_fcu(request).await,
-        ENGINE_NEW_PAYLOAD_V1
-        | ENGINE_NEW_PAYLOAD_V2
-        | ENGINE_NEW_PAYLOAD_V3
-        | ENGINE_NEW_PAYLOAD_V4 => multiplexer.handle_new_payload(request).await,
-        ETH_SYNCING => multiplexer.handle_syncing(request).await,
-        "eth_chainId" => multiplexer.handle_chain_id(request).await,
-        ENGINE_EXCHANGE_CAPABILITIES => multiplexer.handle_engine_capabilities(request).await,
-        "engine_getBlobsV1"
-        | "eth_getBlockByNumber"
-        | "eth_getBlockByHash"
-        | "eth_getLogs"
-        | "eth_call"
-        | "eth_blockNumber"
-        | ENGINE_GET_PAYLOAD_BODIES_BY_HASH_V1
-        | ENGINE_GET_PAYLOAD_BODIES_BY_RANGE_V1
-        | ENGINE_GET_CLIENT_VERSION_V1 => multiplexer.proxy_directly(request).await,
-        ENGINE_GET_PAYLOAD_V1
-        | ENGINE_GET_PAYLOAD_V2
-        | ENGINE_GET_PAYLOAD_V3
-        | ENGINE_GET_PAYLOAD_V4 => multiplexer.handle_get_payload(request).await,
-        method => Err(ErrorResponse::unsupported_method(request.id, method)),
+    let config = &state.multiplexer.config;
+    let method = request.method.clone();
+    let start = Instant::now();
+    let result = dispatch(&state.multiplexer, priority, controller_name, request).await;
+    record_request_metrics(config, &method, controller_name, start, &result);
+    result
+}
+
+/// How eleel answers a method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Handling {
+    /// Forwarded to the EL unchanged.
+    Proxied,
+    /// Sent to the EL by the controller, and answered from the cache for clients.
+    Cached,
+    /// Answered by eleel itself, possibly with information from the EL.
+    Synthesized,
+}
+
+/// Handler for a method, which may behave differently for the controller and clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Handler {
+    ForkchoiceUpdated,
+    NewPayload,
+    GetPayload,
+    Syncing,
+    ChainId,
+    ExchangeCapabilities,
+    ClientVersion,
+    Proxy,
+}
+
+struct MethodEntry {
+    name: &'static str,
+    handling: Handling,
+    handler: Handler,
+}
+
+const fn method(name: &'static str, handling: Handling, handler: Handler) -> MethodEntry {
+    MethodEntry {
+        name,
+        handling,
+        handler,
     }
 }
 
-async fn handle_controller_json_rpc(
-    State(state): State<Arc<AppState>>,
-    TypedHeader(jwt_token_str): TypedHeader<Authorization<Bearer>>,
-    maybe_request: Result<Json<Request>, JsonRejection>,
-) -> Result<Json<Response>, Json<ErrorResponse>> {
-    let jwt_secret = &state.controller_jwt_secret;
-    let multiplexer = &state.multiplexer;
+/// Every method supported by eleel.
+///
+/// Methods not listed here are rejected as unsupported, unless configured with `--proxy-methods`.
+const METHODS: &[MethodEntry] = &[
+    method(
+        ENGINE_FORKCHOICE_UPDATED_V1,
+        Handling::Cached,
+        Handler::ForkchoiceUpdated,
+    ),
+    method(
+        ENGINE_FORKCHOICE_UPDATED_V2,
+        Handling::Cached,
+        Handler::ForkchoiceUpdated,
+    ),
+    method(
+        ENGINE_FORKCHOICE_UPDATED_V3,
+        Handling::Cached,
+        Handler::ForkchoiceUpdated,
+    ),
+    method(ENGINE_NEW_PAYLOAD_V1, Handling::Cached, Handler::NewPayload),
+    method(ENGINE_NEW_PAYLOAD_V2, Handling::Cached, Handler::NewPayload),
+    method(ENGINE_NEW_PAYLOAD_V3, Handling::Cached, Handler::NewPayload),
+    method(ENGINE_NEW_PAYLOAD_V4, Handling::Cached, Handler::NewPayload),
+    method(
+        ENGINE_GET_PAYLOAD_V1,
+        Handling::Synthesized,
+        Handler::GetPayload,
+    ),
+    method(
+        ENGINE_GET_PAYLOAD_V2,
+        Handling::Synthesized,
+        Handler::GetPayload,
+    ),
+    method(
+        ENGINE_GET_PAYLOAD_V3,
+        Handling::Synthesized,
+        Handler::GetPayload,
+    ),
+    method(
+        ENGINE_GET_PAYLOAD_V4,
+        Handling::Synthesized,
+        Handler::GetPayload,
+    ),
+    method(
+        ENGINE_GET_PAYLOAD_V5,
+        Handling::Synthesized,
+        Handler::GetPayload,
+    ),
+    method(ETH_SYNCING, Handling::Synthesized, Handler::Syncing),
+    method("eth_chainId", Handling::Synthesized, Handler::ChainId),
+    method(
+        ENGINE_EXCHANGE_CAPABILITIES,
+        Handling::Synthesized,
+        Handler::ExchangeCapabilities,
+    ),
+    method("engine_getBlobsV1", Handling::Proxied, Handler::Proxy),
+    method("engine_getBlobsV2", Handling::Proxied, Handler::Proxy),
+    method("eth_getBlockByNumber", Handling::Proxied, Handler::Proxy),
+    method("eth_getBlockByHash", Handling::Proxied, Handler::Proxy),
+    method("eth_getLogs", Handling::Proxied, Handler::Proxy),
+    method("eth_call", Handling::Proxied, Handler::Proxy),
+    method("eth_blockNumber", Handling::Proxied, Handler::Proxy),
+    method(
+        ENGINE_GET_PAYLOAD_BODIES_BY_HASH_V1,
+        Handling::Proxied,
+        Handler::Proxy,
+    ),
+    method(
+        ENGINE_GET_PAYLOAD_BODIES_BY_RANGE_V1,
+        Handling::Proxied,
+        Handler::Proxy,
+    ),
+    method(
+        ENGINE_GET_CLIENT_VERSION_V1,
+        Handling::Synthesized,
+        Handler::ClientVersion,
+    ),
+];
 
-    // Check JWT auth.
-    if let Err(e) = verify_single_token(jwt_token_str.token(), jwt_secret) {
-        tracing::warn!(
-            error = ?e,
-            "Controller JWT auth failed"
-        );
-        return Err(Json(ErrorResponse::parse_error_generic(
-            serde_json::json!(0),
-            e,
-        )));
+fn method_entry(name: &str) -> Option<&'static MethodEntry> {
+    METHODS.iter().find(|entry| entry.name == name)
+}
+
+/// Metric label for a method.
+///
+/// Only methods in the table are recorded under their own name, so that clients can't create
+/// arbitrary label values. Methods proxied due to a `--proxy-methods` pattern share a single
+/// label, and all other methods are recorded as unsupported.
+fn method_label<'a>(config: &Config, method: &'a str) -> &'a str {
+    if method_entry(method).is_some() {
+        method
+    } else if config.is_extra_proxy_method(method) {
+        metrics::PROXIED_EXTRA_METHOD
+    } else {
+        metrics::UNSUPPORTED_METHOD
     }
+}
 
-    let Json(request) = maybe_request
-        .map_err(|e| ErrorResponse::parse_error_generic(serde_json::json!(0), e.body_text()))?;
-
-    match request.method.as_str() {
-        ENGINE_FORKCHOICE_UPDATED_V1
-        | ENGINE_FORKCHOICE_UPDATED_V2
-        | ENGINE_FORKCHOICE_UPDATED_V3 => multiplexer.handle_controller_fcu(request).await,
-        ENGINE_NEW_PAYLOAD_V1
-        | ENGINE_NEW_PAYLOAD_V2
-        | ENGINE_NEW_PAYLOAD_V3
-        | ENGINE_NEW_PAYLOAD_V4 => multiplexer.handle_controller_new_payload(request).await,
-        ETH_SYNCING => multiplexer.handle_syncing(request).await,
-        "eth_chainId" => multiplexer.handle_chain_id(request).await,
-        ENGINE_EXCHANGE_CAPABILITIES => multiplexer.handle_engine_capabilities(request).await,
-        "engine_getBlobsV1"
-        | "eth_getBlockByNumber"
-        | "eth_getBlockByHash"
-        | "eth_getLogs"
-        | "eth_call"
-        | "eth_blockNumber"
-        | ENGINE_GET_PAYLOAD_BODIES_BY_HASH_V1
-        | ENGINE_GET_PAYLOAD_BODIES_BY_RANGE_V1
-        | ENGINE_GET_CLIENT_VERSION_V1 => multiplexer.proxy_directly(request).await,
-        ENGINE_GET_PAYLOAD_V1
-        | ENGINE_GET_PAYLOAD_V2
-        | ENGINE_GET_PAYLOAD_V3
-        | ENGINE_GET_PAYLOAD_V4 => multiplexer.handle_get_payload(request).await,
-        method => Err(ErrorResponse::unsupported_method(request.id, method)),
+/// Determine how to handle `method`, or `None` if it is unsupported.
+///
+/// This accounts for methods added to or removed from the proxied methods by the config.
+fn resolve_method(config: &Config, method: &str) -> Option<(Handling, Handler)> {
+    let (handling, handler) = match method_entry(method) {
+        Some(entry) => (entry.handling, entry.handler),
+        None if config.is_extra_proxy_method(method) => (Handling::Proxied, Handler::Proxy),
+        None => return None,
+    };
+    if handler == Handler::Proxy && config.is_proxy_denied(method) {
+        return None;
+    }
+    Some((handling, handler))
+}
+
+/// Check whether eleel supports `method`.
+fn is_supported_method(config: &Config, method: &str) -> bool {
+    resolve_method(config, method).is_some()
+}
+
+/// Engine API methods supported by eleel, as advertised to the EL in `engine_exchangeCapabilities`.
+fn supported_engine_methods(config: &Config) -> Vec<&'static str> {
+    METHODS
+        .iter()
+        .map(|entry| entry.name)
+        .filter(|name| name.starts_with("engine_") && *name != ENGINE_EXCHANGE_CAPABILITIES)
+        .filter(|name| is_supported_method(config, name))
+        .collect()
+}
+
+/// Dispatch a request to the handler for its method, according to the role of the caller.
+async fn dispatch<E: EthSpec>(
+    multiplexer: &Multiplexer<E>,
+    priority: Priority,
+    client_id: &str,
+    request: Request,
+) -> Result<Response, ErrorResponse> {
+    let Some((handling, handler)) = resolve_method(&multiplexer.config, &request.method) else {
+        return Err(ErrorResponse::unsupported_method(
+            request.id,
+            &request.method,
+        ));
+    };
+    tracing::trace!(
+        method = %request.method,
+        ?handling,
+        ?priority,
+        "dispatching request"
+    );
+
+    let is_controller = priority == Priority::Controller;
+    match handler {
+        Handler::ForkchoiceUpdated if is_controller => {
+            multiplexer.handle_controller_fcu(request).await
+        }
+        Handler::ForkchoiceUpdated => multiplexer.handle_fcu(request).await,
+        Handler::NewPayload if is_controller => {
+            multiplexer.handle_controller_new_payload(request).await
+        }
+        Handler::NewPayload => multiplexer.handle_new_payload(request).await,
+        Handler::GetPayload => multiplexer.handle_get_payload(request, priority).await,
+        Handler::Syncing => multiplexer.handle_syncing(request, priority).await,
+        Handler::ChainId => multiplexer.handle_chain_id(request).await,
+        Handler::ExchangeCapabilities => {
+            multiplexer
+                .handle_engine_capabilities(request, priority, client_id)
+                .await
+        }
+        Handler::ClientVersion => multiplexer.handle_client_version(request, priority).await,
+        Handler::Proxy => multiplexer.proxy_directly(request, priority).await,
+    }
+}
+
+fn record_request_metrics(
+    config: &Config,
+    method: &str,
+    client_id: &str,
+    start: Instant,
+    result: &Result<Response, ErrorResponse>,
+) {
+    // Avoid creating a metric label for every unsupported method a client sends us.
+    let method = match result {
+        Err(e) if e.error.code == ErrorCode::MethodNotFound => metrics::UNSUPPORTED_METHOD,
+        _ => method_label(config, method),
+    };
+    metrics::inc_request(method, client_id);
+    metrics::observe_request_duration(method, start.elapsed());
+    if result.is_err() {
+        metrics::inc_response(method, metrics::ResponseKind::Error);
     }
-    .map(Json)
-    .map_err(Json)
 }
 
 async fn handle_health() -> impl IntoResponse {
     StatusCode::OK
 }
+
+async fn handle_metrics<E: EthSpec>(State(state): State<Arc<AppState<E>>>) -> impl IntoResponse {
+    state.multiplexer.update_cache_metrics().await;
+    state.multiplexer.update_client_metrics();
+    match metrics::gather() {
+        Ok(body) => (StatusCode::OK, body),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
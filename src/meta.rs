@@ -1,58 +1,259 @@
 //! Support for meta methods which return information about the EL itself.
 use crate::{
+    metrics,
     multiplexer::Multiplexer,
+    scheduler::Priority,
     types::{ErrorResponse, JsonValue, QuantityU64, Request, Response},
 };
 use eth2::types::EthSpec;
+use git_version::git_version;
+use serde::Serialize;
 use std::time::Duration;
+use tokio::time::Instant;
+
+/// Maximum age of the EL's capabilities before they are fetched again.
+const CAPABILITIES_CACHE_SECS: u64 = 15 * 60;
+
+/// Abbreviated hash of the commit eleel was built from.
+const GIT_COMMIT: &str = git_version!(
+    args = ["--always", "--abbrev=8", "--exclude=*"],
+    fallback = "00000000"
+);
+
+/// Client version in the format of `engine_getClientVersionV1`.
+#[derive(Serialize)]
+struct ClientVersionV1 {
+    code: &'static str,
+    name: &'static str,
+    version: &'static str,
+    commit: String,
+}
+
+impl ClientVersionV1 {
+    fn eleel() -> Self {
+        // Eleel has no registered client code, so use one that is unlikely to clash.
+        Self {
+            code: "EL",
+            name: "Eleel",
+            version: env!("CARGO_PKG_VERSION"),
+            commit: format!("0x{}", GIT_COMMIT.get(..8).unwrap_or(GIT_COMMIT)),
+        }
+    }
+}
+
+/// Capabilities of the EL, intersected with the methods supported by eleel.
+pub struct CachedCapabilities {
+    fetched: Instant,
+    /// Index of the engine the capabilities were fetched from.
+    engine_index: usize,
+    capabilities: Vec<String>,
+}
+
+/// The result of `eth_syncing` while a sync is in progress.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SyncingStatus {
+    starting_block: QuantityU64,
+    current_block: QuantityU64,
+    highest_block: QuantityU64,
+}
 
 impl<E: EthSpec> Multiplexer<E> {
-    pub async fn handle_syncing(&self, request: Request) -> Result<Response, ErrorResponse> {
-        // TODO: actually check EL status, maybe with a cache
+    /// Report the sync status of the EL, cached briefly and shared between all clients.
+    ///
+    /// If the EL claims to be synced but the controller hasn't sent an fcU recently, then the
+    /// EL's view of the chain may be stale, so we report to clients that we are syncing instead.
+    /// The controller always receives the EL's status, so that its own silence isn't reflected
+    /// back to it.
+    pub async fn handle_syncing(
+        &self,
+        request: Request,
+        priority: Priority,
+    ) -> Result<Response, ErrorResponse> {
+        let method = request.method.clone();
         let (id, _) = request.parse_as::<Vec<()>>()?;
-        Response::new(id, false)
+
+        let el_syncing = self.get_el_syncing(&method, &id, priority).await?;
+        if priority == Priority::Controller || el_syncing != JsonValue::Bool(false) {
+            return Response::new(id, el_syncing);
+        }
+
+        let fcu_stale_threshold = Duration::from_secs(self.config.controller_fcu_stale_secs);
+        let controller_fcu_recent = self
+            .last_controller_fcu
+            .lock()
+            .await
+            .is_some_and(|last_fcu| last_fcu.elapsed() <= fcu_stale_threshold);
+        if controller_fcu_recent {
+            return Response::new(id, false);
+        }
+
+        tracing::debug!("reporting syncing due to lack of recent fcU from controller");
+        let current_block = self
+            .new_payload_cache
+            .lock()
+            .await
+            .iter()
+            .map(|(_, entry)| entry.block_number)
+            .max()
+            .unwrap_or(0);
+        let status = SyncingStatus {
+            starting_block: QuantityU64 {
+                value: current_block,
+            },
+            current_block: QuantityU64 {
+                value: current_block,
+            },
+            highest_block: QuantityU64 {
+                value: current_block,
+            },
+        };
+        Response::new(id, status)
     }
 
-    pub async fn handle_chain_id(&self, request: Request) -> Result<Response, ErrorResponse> {
-        let (id, _) = request.parse_as::<Vec<()>>()?;
+    /// Get the EL's response to `eth_syncing`, from the cache if it is fresh enough.
+    ///
+    /// The controller always queries the EL and only writes its result to the cache. The lock is
+    /// never held while waiting for the EL, so the controller can't be stuck behind a client that
+    /// is queued for the EL.
+    async fn get_el_syncing(
+        &self,
+        method: &str,
+        id: &JsonValue,
+        priority: Priority,
+    ) -> Result<JsonValue, ErrorResponse> {
+        let max_age = Duration::from_secs(self.config.syncing_cache_secs);
+        if priority != Priority::Controller {
+            if let Some((fetched, status)) = self.syncing_cache.lock().await.as_ref() {
+                if fetched.elapsed() <= max_age {
+                    return Ok(status.clone());
+                }
+            }
+        }
 
-        // TODO: dynamic timeout
-        let timeout = Duration::from_secs(1);
-        let chain_id = self
-            .engine
+        let _permit = self.acquire_engine(id, priority).await?;
+        let _timer = metrics::upstream_timer(method);
+        let timeout = self.config.proxy_timeout(method);
+        let status: JsonValue = self
+            .engine()
             .api
-            .get_chain_id(timeout)
+            .rpc_request(method, serde_json::json!([]), timeout)
             .await
             .map_err(|e| ErrorResponse::parse_error_generic(id.clone(), format!("{e:?}")))?;
+
+        *self.syncing_cache.lock().await = Some((Instant::now(), status.clone()));
+        Ok(status)
+    }
+
+    /// Respond with the chain ID of the EL, which is checked at startup.
+    pub async fn handle_chain_id(&self, request: Request) -> Result<Response, ErrorResponse> {
+        let (id, _) = request.parse_as::<Vec<()>>()?;
         let result = QuantityU64 {
-            value: chain_id.into(),
+            value: self.chain_id,
         };
         Response::new(id, result)
     }
 
+    /// Respond with the methods supported by both the EL and eleel.
+    ///
+    /// The capabilities advertised by the CL are recorded against its key ID, so that clients
+    /// lacking methods used by the controller can be identified.
     pub async fn handle_engine_capabilities(
         &self,
         request: Request,
+        priority: Priority,
+        client_id: &str,
     ) -> Result<Response, ErrorResponse> {
-        let (id, (_cl_capabilities,)) = request.parse_as::<(Vec<String>,)>()?;
+        let method = request.method.clone();
+        let (id, (cl_capabilities,)) = request.parse_as::<(Vec<String>,)>()?;
+        self.clients.record_capabilities(client_id, cl_capabilities);
+
+        let capabilities = self.get_el_capabilities(&method, &id, priority).await?;
+        Response::new(id, capabilities)
+    }
+
+    /// Get the EL's capabilities supported by eleel, from the cache if they are fresh enough and
+    /// were fetched from the active engine.
+    ///
+    /// As for `eth_syncing`, the controller always queries the EL and the lock is never held while
+    /// waiting for the EL.
+    async fn get_el_capabilities(
+        &self,
+        method: &str,
+        id: &JsonValue,
+        priority: Priority,
+    ) -> Result<Vec<String>, ErrorResponse> {
+        let engine_index = self.engines.active_index();
+        let max_age = Duration::from_secs(CAPABILITIES_CACHE_SECS);
+        if priority != Priority::Controller {
+            if let Some(cached) = self.capabilities_cache.lock().await.as_ref() {
+                if cached.engine_index == engine_index && cached.fetched.elapsed() <= max_age {
+                    return Ok(cached.capabilities.clone());
+                }
+            }
+        }
 
-        let max_age = Duration::from_secs(15 * 60);
-        let engine_capabilities = self
-            .engine
-            .get_engine_capabilities(Some(max_age))
+        let _permit = self.acquire_engine(id, priority).await?;
+        let _timer = metrics::upstream_timer(method);
+        let timeout = self.config.proxy_timeout(method);
+        let params = serde_json::json!([crate::supported_engine_methods(&self.config)]);
+        let el_capabilities: Vec<String> = self
+            .engine()
+            .api
+            .rpc_request(method, params, timeout)
             .await
             .map_err(|e| ErrorResponse::parse_error_generic(id.clone(), format!("{e:?}")))?;
-        Response::new(id, engine_capabilities.to_response())
+
+        let capabilities = el_capabilities
+            .into_iter()
+            .filter(|method| crate::is_supported_method(&self.config, method))
+            .collect::<Vec<_>>();
+        *self.capabilities_cache.lock().await = Some(CachedCapabilities {
+            fetched: Instant::now(),
+            engine_index,
+            capabilities: capabilities.clone(),
+        });
+        Ok(capabilities)
     }
 
-    pub async fn proxy_directly(&self, request: Request) -> Result<Response, ErrorResponse> {
+    /// Respond with the EL's client versions, followed by eleel's.
+    pub async fn handle_client_version(
+        &self,
+        request: Request,
+        priority: Priority,
+    ) -> Result<Response, ErrorResponse> {
+        let id = request.id;
+        let _permit = self.acquire_engine(&id, priority).await?;
+        let _timer = metrics::upstream_timer(&request.method);
+        let timeout = self.config.proxy_timeout(&request.method);
+
+        let mut client_versions: Vec<JsonValue> = self
+            .engine()
+            .api
+            .rpc_request(&request.method, request.params, timeout)
+            .await
+            .map_err(|e| ErrorResponse::parse_error_generic(id.clone(), format!("{e:?}")))?;
+
+        let eleel_version = serde_json::to_value(ClientVersionV1::eleel())
+            .map_err(|e| ErrorResponse::parse_error(id.clone(), e))?;
+        client_versions.push(eleel_version);
+
+        Response::new(id, client_versions)
+    }
+
+    pub async fn proxy_directly(
+        &self,
+        request: Request,
+        priority: Priority,
+    ) -> Result<Response, ErrorResponse> {
         let id = request.id;
+        let _permit = self.acquire_engine(&id, priority).await?;
+        let _timer = metrics::upstream_timer(crate::method_label(&self.config, &request.method));
 
-        // TODO: adjust timeout
-        let timeout = Duration::from_secs(12);
+        let timeout = self.config.proxy_timeout(&request.method);
 
         let result: JsonValue = self
-            .engine
+            .engine()
             .api
             .rpc_request(&request.method, request.params, timeout)
             .await
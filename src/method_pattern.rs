@@ -0,0 +1,176 @@
+//! Matching of JSON-RPC method names against simple glob patterns.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A JSON-RPC method name which may contain `*` wildcards, e.g. `engine_*` or `eth_get*ByHash`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct MethodPattern(String);
+
+/// Prefixes of engine methods which are multiplexed by eleel, and must never be proxied.
+///
+/// `engine_getPayloadV` excludes `engine_getPayloadBodies*`, which may be proxied.
+const MULTIPLEXED_PREFIXES: &[&str] = &[
+    "engine_forkchoiceUpdated",
+    "engine_newPayload",
+    "engine_getPayloadV",
+];
+
+impl MethodPattern {
+    pub fn matches(&self, method: &str) -> bool {
+        glob_match(&self.0, method)
+    }
+
+    /// Parse a pattern for `--proxy-methods`.
+    ///
+    /// Patterns which could match a multiplexed engine method are rejected, as proxying such a
+    /// method (e.g. a future `engine_newPayloadV5`) would bypass the controller entirely.
+    pub fn parse_proxied(s: &str) -> Result<Self, String> {
+        let pattern = s.parse::<Self>()?;
+        if let Some(prefix) = MULTIPLEXED_PREFIXES
+            .iter()
+            .find(|prefix| pattern.may_match_prefix(prefix))
+        {
+            return Err(format!(
+                "pattern `{s}` may match `{prefix}*` methods, which must not be proxied"
+            ));
+        }
+        Ok(pattern)
+    }
+
+    /// Check whether the pattern could match any method starting with `prefix`.
+    fn may_match_prefix(&self, prefix: &str) -> bool {
+        match self.0.split_once('*') {
+            // A wildcard can absorb the remainder of the prefix, and anything after it.
+            Some((literal, _)) => literal.starts_with(prefix) || prefix.starts_with(literal),
+            None => self.0.starts_with(prefix),
+        }
+    }
+}
+
+impl FromStr for MethodPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty method pattern".into());
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl fmt::Display for MethodPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A timeout for requests to methods matching a pattern, written as `PATTERN=MILLIS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MethodTimeout {
+    pub pattern: MethodPattern,
+    pub timeout: Duration,
+}
+
+impl FromStr for MethodTimeout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, millis) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid method timeout `{s}`, expected PATTERN=MILLIS"))?;
+        let millis = millis
+            .parse::<u64>()
+            .map_err(|e| format!("invalid timeout in `{s}`: {e}"))?;
+        Ok(Self {
+            pattern: pattern.parse()?,
+            timeout: Duration::from_millis(millis),
+        })
+    }
+}
+
+/// Match `s` against `pattern`, where `*` matches any sequence of characters (including none).
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+
+    // The part before the first wildcard must be a prefix.
+    let Some(mut rest) = parts.next().and_then(|first| s.strip_prefix(first)) else {
+        return false;
+    };
+
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        // No wildcards: exact match.
+        return rest.is_empty();
+    };
+
+    // Middle parts must appear in order, as early as possible.
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    // The part after the last wildcard must be a suffix.
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("eth_getLogs", "eth_getLogs"));
+        assert!(!glob_match("eth_getLogs", "eth_getLogsV2"));
+        assert!(glob_match("debug_*", "debug_traceBlock"));
+        assert!(glob_match("debug_*", "debug_"));
+        assert!(!glob_match("debug_*", "eth_call"));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("eth_get*ByHash", "eth_getBlockByHash"));
+        assert!(!glob_match("eth_get*ByHash", "eth_getBlockByNumber"));
+        assert!(glob_match("*_get*_*", "eth_getBlock_x"));
+        assert!(!glob_match("a*b*c", "acb"));
+    }
+
+    #[test]
+    fn proxied_patterns() {
+        for allowed in [
+            "eth_getProof",
+            "debug_*",
+            "eth_*",
+            "engine_getBlobsV3",
+            "engine_getPayloadBodiesByHashV2",
+            "engine_getPayloadBodies*",
+        ] {
+            assert!(MethodPattern::parse_proxied(allowed).is_ok(), "{allowed}");
+        }
+        for rejected in [
+            "*",
+            "engine_*",
+            "engine_new*",
+            "engine_newPayloadV5",
+            "engine_forkchoiceUpdated*",
+            "engine_getPayloadV*",
+            "engine_getPayload*",
+            "engine_getPayloadV6",
+        ] {
+            assert!(
+                MethodPattern::parse_proxied(rejected).is_err(),
+                "{rejected}"
+            );
+        }
+    }
+
+    #[test]
+    fn method_timeouts() {
+        let timeout = "debug_*=60000".parse::<MethodTimeout>().unwrap();
+        assert!(timeout.pattern.matches("debug_traceBlock"));
+        assert_eq!(timeout.timeout, Duration::from_secs(60));
+        assert!("debug_*".parse::<MethodTimeout>().is_err());
+        assert!("debug_*=soon".parse::<MethodTimeout>().is_err());
+    }
+}
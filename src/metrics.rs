@@ -0,0 +1,233 @@
+//! Prometheus metrics for requests, cache usage and upstream latency.
+use crate::multiplexer::Multiplexer;
+use eth2::types::EthSpec;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder,
+    HistogramTimer, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::{Duration, UNIX_EPOCH};
+use strum::IntoStaticStr;
+
+/// Label used in place of a client key ID for requests from the controller.
+pub const CONTROLLER: &str = "controller";
+
+/// Label used in place of the method name for unsupported methods (to bound cardinality).
+pub const UNSUPPORTED_METHOD: &str = "unsupported";
+
+/// Label used in place of the method name for methods proxied due to `--proxy-methods`.
+pub const PROXIED_EXTRA_METHOD: &str = "proxied_extra";
+
+pub static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "eleel_requests_total",
+        "Number of JSON-RPC requests received, by method and client key ID",
+        &["method", "client"]
+    )
+    .unwrap()
+});
+
+pub static REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "eleel_request_duration_seconds",
+        "Time taken to respond to a JSON-RPC request, by method",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static RESPONSES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "eleel_responses_total",
+        "Number of JSON-RPC responses sent, by method and kind of response",
+        &["method", "kind"]
+    )
+    .unwrap()
+});
+
+pub static WAIT_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "eleel_wait_duration_seconds",
+        "Time spent waiting for a definite response from the controller, by method",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static CACHE_ENTRIES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "eleel_cache_entries",
+        "Number of entries in each in-memory cache",
+        &["cache"]
+    )
+    .unwrap()
+});
+
+pub static UPSTREAM_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "eleel_upstream_request_duration_seconds",
+        "Time taken for the execution engine to respond to a request, by method",
+        &["method"]
+    )
+    .unwrap()
+});
+
+pub static QUEUE_DELAY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "eleel_upstream_queue_delay_seconds",
+        "Time spent waiting for a slot to send a request to the execution engine, by priority",
+        &["priority"]
+    )
+    .unwrap()
+});
+
+pub static CLIENT_INFO: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "eleel_client_info",
+        "Client version (from the JWT clv claim) of each connected client, by client key ID",
+        &["client", "clv"]
+    )
+    .unwrap()
+});
+
+pub static CLIENT_LAST_SEEN: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "eleel_client_last_seen_seconds",
+        "Unix timestamp of the most recent request from each client, by client key ID",
+        &["client"]
+    )
+    .unwrap()
+});
+
+pub static CLIENT_MISSING_CAPABILITIES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "eleel_client_missing_capabilities",
+        "Number of engine methods advertised by the controller but not by each client",
+        &["client"]
+    )
+    .unwrap()
+});
+
+pub static ENGINE_HEALTHY: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "eleel_engine_healthy",
+        "Whether each upstream execution engine passed its last health check (1) or not (0)",
+        &["url"]
+    )
+    .unwrap()
+});
+
+/// The kind of response sent to a client for a cached method (newPayload or fcU).
+#[derive(Debug, Clone, Copy, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum ResponseKind {
+    /// Definite (VALID/INVALID) status from the cache.
+    CacheHitDefinite,
+    /// Indefinite (SYNCING/ACCEPTED) status from the cache.
+    CacheHitIndefinite,
+    /// SYNCING status synthesised by eleel because nothing was cached.
+    SyntheticSyncing,
+    /// JSON-RPC error.
+    Error,
+}
+
+pub fn inc_request(method: &str, client: &str) {
+    REQUESTS.with_label_values(&[method, client]).inc();
+}
+
+pub fn inc_response(method: &str, kind: ResponseKind) {
+    RESPONSES.with_label_values(&[method, kind.into()]).inc();
+}
+
+pub fn observe_request_duration(method: &str, duration: Duration) {
+    REQUEST_DURATION
+        .with_label_values(&[method])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn observe_wait_duration(method: &str, duration: Duration) {
+    WAIT_DURATION
+        .with_label_values(&[method])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn observe_queue_delay(priority: &str, duration: Duration) {
+    QUEUE_DELAY
+        .with_label_values(&[priority])
+        .observe(duration.as_secs_f64());
+}
+
+/// Label used for clients which do not send a `clv` claim.
+const UNKNOWN_CLIENT_VERSION: &str = "unknown";
+
+/// Replace the version label for `client` in the client info metric.
+pub fn set_client_version(client: &str, old_clv: Option<&str>, new_clv: Option<&str>) {
+    // Removal fails harmlessly if the client has not been seen before.
+    let _ = CLIENT_INFO.remove_label_values(&[client, old_clv.unwrap_or(UNKNOWN_CLIENT_VERSION)]);
+    CLIENT_INFO
+        .with_label_values(&[client, new_clv.unwrap_or(UNKNOWN_CLIENT_VERSION)])
+        .set(1);
+}
+
+pub fn set_missing_capabilities(client: &str, missing: usize) {
+    CLIENT_MISSING_CAPABILITIES
+        .with_label_values(&[client])
+        .set(missing as i64);
+}
+
+pub fn set_engine_healthy(url: &str, healthy: bool) {
+    ENGINE_HEALTHY
+        .with_label_values(&[url])
+        .set(i64::from(healthy));
+}
+
+/// Start a timer for a request to the upstream execution engine, which is recorded on drop.
+pub fn upstream_timer(method: &str) -> HistogramTimer {
+    UPSTREAM_DURATION.with_label_values(&[method]).start_timer()
+}
+
+/// Encode all registered metrics in the Prometheus text format.
+pub fn gather() -> Result<String, String> {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|e| format!("unable to encode metrics: {e:?}"))?;
+    String::from_utf8(buffer).map_err(|e| format!("invalid metrics encoding: {e:?}"))
+}
+
+impl<E: EthSpec> Multiplexer<E> {
+    /// Update the cache occupancy gauges. Called prior to each scrape.
+    pub async fn update_cache_metrics(&self) {
+        let caches = [
+            ("fcu", self.fcu_cache.lock().await.len()),
+            ("new_payload", self.new_payload_cache.lock().await.len()),
+            (
+                "justified_block",
+                self.justified_block_cache.lock().await.len(),
+            ),
+            (
+                "finalized_block",
+                self.finalized_block_cache.lock().await.len(),
+            ),
+            ("block_tree", self.block_tree.lock().await.len()),
+        ];
+        let builder_caches = self.payload_builder.lock().await.cache_lens();
+
+        for (cache, len) in caches.into_iter().chain(builder_caches) {
+            CACHE_ENTRIES.with_label_values(&[cache]).set(len as i64);
+        }
+    }
+
+    /// Update the last-seen time of each client. Called prior to each scrape.
+    pub fn update_client_metrics(&self) {
+        for (client, info) in self.clients.clients() {
+            let last_seen = info
+                .last_seen
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            CLIENT_LAST_SEEN
+                .with_label_values(&[client.as_str()])
+                .set(last_seen as i64);
+        }
+    }
+}
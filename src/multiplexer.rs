@@ -2,29 +2,55 @@
 //!
 //! We may cache more here in future (e.g. payload bodies for reconstruction).
 use crate::{
+    block_tree::BlockTree,
+    clients::ClientRegistry,
     config::Config,
+    engines::Engines,
+    genesis,
+    meta::CachedCapabilities,
     payload_builder::PayloadBuilder,
-    types::{Auth, Engine, JsonForkchoiceStateV1, JsonPayloadStatusV1, TaskExecutor},
+    scheduler::Scheduler,
+    types::{JsonForkchoiceStateV1, JsonPayloadStatusV1, JsonValue, TaskExecutor},
+    waiters::Waiters,
 };
 use eth2::types::{ChainSpec, EthSpec, ExecutionBlockHash};
-use execution_layer::HttpJsonRpc;
+use eth2_network_config::Eth2NetworkConfig;
 use lru::LruCache;
 use slog::Logger;
 use std::marker::PhantomData;
 use std::num::NonZeroUsize;
-use std::path::PathBuf;
-use std::str::FromStr;
-use std::time::Duration;
 use tokio::sync::Mutex;
+use tokio::time::Instant;
 
 pub struct Multiplexer<E: EthSpec> {
-    pub engine: Engine,
+    /// Upstream execution engines, only one of which is active at a time.
+    pub engines: Engines,
+    /// Prioritises requests to the active engine.
+    pub scheduler: Scheduler,
     pub fcu_cache: Mutex<LruCache<JsonForkchoiceStateV1, JsonPayloadStatusV1>>,
     pub new_payload_cache: Mutex<LruCache<ExecutionBlockHash, NewPayloadCacheEntry>>,
     pub justified_block_cache: Mutex<LruCache<ExecutionBlockHash, ()>>,
     pub finalized_block_cache: Mutex<LruCache<ExecutionBlockHash, ()>>,
+    /// Blocks from the controller's newPayloads, used to match fcUs from clients.
+    pub block_tree: Mutex<BlockTree>,
+    /// Clients waiting on a newPayload status for a block hash.
+    pub new_payload_waiters: Waiters<ExecutionBlockHash>,
+    /// Clients waiting on an fcU status for a head block hash.
+    pub fcu_waiters: Waiters<ExecutionBlockHash>,
     pub payload_builder: Mutex<PayloadBuilder<E>>,
+    /// Most recent response to `eth_syncing` from the EL, and when it was fetched.
+    pub syncing_cache: Mutex<Option<(Instant, JsonValue)>>,
+    /// Most recent response to `engine_exchangeCapabilities` from the EL.
+    pub capabilities_cache: Mutex<Option<CachedCapabilities>>,
+    /// Clients which have connected, including the controller.
+    pub clients: ClientRegistry,
+    /// Time at which the controller last sent an fcU.
+    pub last_controller_fcu: Mutex<Option<Instant>>,
+    /// Serialises writes of the on-disk snapshot.
+    pub persist_lock: Mutex<()>,
     pub genesis_time: u64,
+    /// Chain ID of the EL, which matches the network's deposit chain ID.
+    pub chain_id: u64,
     pub spec: ChainSpec,
     pub config: Config,
     pub log: Logger,
@@ -37,24 +63,14 @@ pub struct NewPayloadCacheEntry {
 }
 
 impl<E: EthSpec> Multiplexer<E> {
-    pub async fn new(config: Config, executor: TaskExecutor, log: Logger) -> Result<Self, String> {
-        let engine: Engine = {
-            let jwt_secret_path = PathBuf::from(&config.ee_jwt_secret);
-            let jwt_id = Some("eleel".to_string());
-            let jwt_version = None;
-
-            let execution_timeout_multiplier = Some(2);
-
-            let auth = Auth::new_with_path(jwt_secret_path, jwt_id, jwt_version)
-                .map_err(|e| format!("JWT secret error: {e:?}"))?;
-
-            let url =
-                FromStr::from_str(&config.ee_url).map_err(|e| format!("Invalid EL URL: {e:?}"))?;
-            let api = HttpJsonRpc::new_with_auth(url, auth, execution_timeout_multiplier)
-                .map_err(|e| format!("Error connecting to EL: {e:?}"))?;
-
-            Engine::new(api, executor, &log)
-        };
+    pub async fn new(
+        config: Config,
+        network_config: Eth2NetworkConfig,
+        executor: TaskExecutor,
+        log: Logger,
+    ) -> Result<Self, String> {
+        let engines = Engines::new(&config, executor, &log)?;
+        let scheduler = Scheduler::new(config.ee_max_concurrent_requests, config.ee_queue_size)?;
 
         let fcu_cache = Mutex::new(LruCache::new(
             NonZeroUsize::new(config.fcu_cache_size).ok_or("invalid cache size")?,
@@ -68,13 +84,19 @@ impl<E: EthSpec> Multiplexer<E> {
         let finalized_block_cache = Mutex::new(LruCache::new(
             NonZeroUsize::new(config.justified_block_cache_size).ok_or("invalid cache size")?,
         ));
+        let block_tree = Mutex::new(BlockTree::new(config.block_tree_max_blocks));
         let payload_builder = Mutex::new(PayloadBuilder::new(
             NonZeroUsize::new(config.payload_builder_cache_size).ok_or("invalid cache size")?,
             &config.payload_builder_extra_data,
         ));
 
         // Derived values.
-        let spec = config.network.network.chain_spec::<E>()?;
+        let spec = network_config.chain_spec::<E>()?;
+        tracing::info!(
+            preset = ?E::spec_name(),
+            config_name = ?network_config.config.config_name,
+            "network config"
+        );
         tracing::info!(
             bellatrix_fork_epoch = ?spec.bellatrix_fork_epoch,
             capella_fork_epoch = ?spec.capella_fork_epoch,
@@ -82,23 +104,28 @@ impl<E: EthSpec> Multiplexer<E> {
             electra_fork_epoch = ?spec.electra_fork_epoch,
             "fork schedule"
         );
-        let genesis_state_timeout = Duration::from_secs(180);
-        let genesis_state = config
-            .network
-            .network
-            .genesis_state::<E>(None, genesis_state_timeout, &log)
-            .await?
-            .ok_or("no genesis state")?;
-        let genesis_time = genesis_state.genesis_time();
+        let genesis_time =
+            genesis::genesis_time::<E>(&config, &network_config, &spec, &log).await?;
+        let chain_id = engines.select_initial(&spec).await?;
 
         Ok(Self {
-            engine,
+            engines,
+            scheduler,
             fcu_cache,
             new_payload_cache,
             justified_block_cache,
             finalized_block_cache,
+            block_tree,
+            new_payload_waiters: Waiters::new(),
+            fcu_waiters: Waiters::new(),
             payload_builder,
+            syncing_cache: Mutex::new(None),
+            capabilities_cache: Mutex::new(None),
+            clients: ClientRegistry::default(),
+            last_controller_fcu: Mutex::new(None),
+            persist_lock: Mutex::new(()),
             genesis_time,
+            chain_id,
             spec,
             config,
             log,
@@ -1,12 +1,16 @@
 //! Handler for new payload.
 use crate::{
+    block_tree::Block,
+    metrics::{self, ResponseKind},
     multiplexer::{Multiplexer, NewPayloadCacheEntry},
+    scheduler::Priority,
     types::{
         ErrorResponse, JsonExecutionPayload, JsonExecutionRequests, JsonPayloadStatusV1,
         JsonPayloadStatusV1Status, JsonValue, NewPayloadRequest, NewPayloadRequestBellatrix,
-        NewPayloadRequestCapella, NewPayloadRequestDeneb, NewPayloadRequestElectra, QuantityU64,
-        Request, Response,
+        NewPayloadRequestCapella, NewPayloadRequestDeneb, NewPayloadRequestElectra,
+        NewPayloadRequestFulu, QuantityU64, Request, Response,
     },
+    waiters::wait_until,
 };
 use eth2::types::{
     EthSpec, ExecutionBlockHash, ExecutionPayload, ExecutionRequests, ForkName, Hash256, Slot,
@@ -15,7 +19,8 @@ use eth2::types::{
 use execution_layer::http::{
     ENGINE_NEW_PAYLOAD_V1, ENGINE_NEW_PAYLOAD_V2, ENGINE_NEW_PAYLOAD_V3, ENGINE_NEW_PAYLOAD_V4,
 };
-use std::time::{Duration, Instant};
+use std::time::Duration;
+use tokio::time::Instant;
 
 impl<E: EthSpec> Multiplexer<E> {
     pub async fn handle_controller_new_payload(
@@ -24,6 +29,7 @@ impl<E: EthSpec> Multiplexer<E> {
     ) -> Result<Response, ErrorResponse> {
         let method = request.method.clone();
         tracing::info!(method = method, "processing payload from controller");
+        let replay_params = self.engines.has_backups().then(|| request.params.clone());
         let (
             id,
             json_execution_payload,
@@ -40,16 +46,27 @@ impl<E: EthSpec> Multiplexer<E> {
             versioned_hashes,
             parent_beacon_block_root,
             execution_requests.as_ref(),
-        );
+        )
+        .map_err(|message| ErrorResponse::invalid_request(id.clone(), message))?;
         let status = if let Some(status) = self.get_cached_payload_status(&block_hash, true).await {
             status
         } else {
             // Send payload to the real EL.
-            match self.engine.api.new_payload(new_payload_request).await {
+            let permit = self.acquire_engine(&id, Priority::Controller).await?;
+            let timer = metrics::upstream_timer(&method);
+            let result = self.engine().api.new_payload(new_payload_request).await;
+            drop(timer);
+            drop(permit);
+            match result {
                 Ok(status) => {
                     let json_status = JsonPayloadStatusV1::from(status);
 
-                    // Update newPayload cache.
+                    // Remember the payload so that it can be replayed to a backup EL.
+                    if let Some(params) = replay_params {
+                        self.engines.record_new_payload(method.clone(), params);
+                    }
+
+                    // Update newPayload cache and wake any clients waiting on this payload.
                     self.new_payload_cache.lock().await.put(
                         block_hash,
                         NewPayloadCacheEntry {
@@ -57,6 +74,15 @@ impl<E: EthSpec> Multiplexer<E> {
                             block_number,
                         },
                     );
+                    self.block_tree.lock().await.insert(
+                        block_hash,
+                        Block {
+                            parent_hash: execution_payload.parent_hash(),
+                            block_number,
+                            status: json_status.status,
+                        },
+                    );
+                    self.new_payload_waiters.notify(&block_hash);
 
                     // Update payload builder.
                     self.register_canonical_payload(&execution_payload, json_status.status)
@@ -81,6 +107,7 @@ impl<E: EthSpec> Multiplexer<E> {
 
     pub async fn handle_new_payload(&self, request: Request) -> Result<Response, ErrorResponse> {
         tracing::info!("processing new payload from client");
+        let method = request.method.clone();
         let (
             id,
             json_execution_payload,
@@ -97,7 +124,8 @@ impl<E: EthSpec> Multiplexer<E> {
             versioned_hashes,
             parent_beacon_block_root,
             execution_requests.as_ref(),
-        );
+        )
+        .map_err(|message| ErrorResponse::invalid_request(id.clone(), message))?;
 
         // Check block hash prior to keying cache. This prevents responding with an incorrect
         // cached response for a request with a mismatch/invalid block hash.
@@ -119,19 +147,29 @@ impl<E: EthSpec> Multiplexer<E> {
         let is_recent = self.is_recent_payload(block_number).await;
         if is_recent {
             let start = Instant::now();
-            while start.elapsed().as_millis() < self.config.new_payload_wait_millis {
+            let deadline = start + Duration::from_millis(self.config.new_payload_wait_millis);
+            let mut rx = self.new_payload_waiters.subscribe(block_hash);
+            loop {
                 if let Some(status) = self.get_cached_payload_status(&block_hash, true).await {
+                    metrics::observe_wait_duration(&method, start.elapsed());
+                    metrics::inc_response(&method, ResponseKind::CacheHitDefinite);
                     return Response::new(id, status);
                 }
-                tokio::time::sleep(Duration::from_millis(50)).await;
+                if !wait_until(&mut rx, deadline).await {
+                    break;
+                }
             }
+            metrics::observe_wait_duration(&method, start.elapsed());
         }
 
         // Try again to get any status from the cache, or fall back on a SYNCING response.
         let status = if let Some(status) = self.get_cached_payload_status(&block_hash, false).await
         {
-            if !Self::is_definite(&status) {
+            if Self::is_definite(&status) {
+                metrics::inc_response(&method, ResponseKind::CacheHitDefinite);
+            } else {
                 tracing::info!("sending indefinite status on newPayload");
+                metrics::inc_response(&method, ResponseKind::CacheHitIndefinite);
             }
             status
         } else {
@@ -155,6 +193,7 @@ impl<E: EthSpec> Multiplexer<E> {
             } else {
                 tracing::info!("sending instant SYNCING response for old newPayload");
             }
+            metrics::inc_response(&method, ResponseKind::SyntheticSyncing);
             // Synthetic syncing response.
             JsonPayloadStatusV1 {
                 status: JsonPayloadStatusV1Status::Syncing,
@@ -171,8 +210,8 @@ impl<E: EthSpec> Multiplexer<E> {
         versioned_hashes: Option<Vec<VersionedHash>>,
         parent_beacon_block_root: Option<Hash256>,
         execution_requests: Option<&'a ExecutionRequests<E>>,
-    ) -> NewPayloadRequest<'a, E> {
-        match execution_payload {
+    ) -> Result<NewPayloadRequest<'a, E>, String> {
+        let request = match execution_payload {
             ExecutionPayload::Bellatrix(execution_payload) => {
                 NewPayloadRequest::Bellatrix(NewPayloadRequestBellatrix { execution_payload })
             }
@@ -190,8 +229,8 @@ impl<E: EthSpec> Multiplexer<E> {
                 })
             }
             ExecutionPayload::Electra(execution_payload) => {
-                // TODO(Electra): error handling would probably be good here
-                let execution_requests = execution_requests.unwrap();
+                let execution_requests =
+                    execution_requests.ok_or("missing execution requests for Electra payload")?;
                 NewPayloadRequest::Electra(NewPayloadRequestElectra {
                     execution_payload,
                     versioned_hashes: versioned_hashes.unwrap_or_default(),
@@ -199,10 +238,18 @@ impl<E: EthSpec> Multiplexer<E> {
                     execution_requests,
                 })
             }
-            ExecutionPayload::Fulu(_) => {
-                todo!("Fulu")
+            ExecutionPayload::Fulu(execution_payload) => {
+                let execution_requests =
+                    execution_requests.ok_or("missing execution requests for Fulu payload")?;
+                NewPayloadRequest::Fulu(NewPayloadRequestFulu {
+                    execution_payload,
+                    versioned_hashes: versioned_hashes.unwrap_or_default(),
+                    parent_beacon_block_root: parent_beacon_block_root.unwrap_or_default(),
+                    execution_requests,
+                })
             }
-        }
+        };
+        Ok(request)
     }
 
     #[allow(clippy::type_complexity)]
@@ -228,7 +275,7 @@ impl<E: EthSpec> Multiplexer<E> {
                 if params.len() != 4 {
                     return Err(ErrorResponse::parse_error_generic(
                         id,
-                        "wrong number of parameters for newPayloadV3".to_string(),
+                        "wrong number of parameters for newPayloadV4".to_string(),
                     ));
                 }
                 let versioned_hashes = serde_json::from_value(params[1].clone())
@@ -291,15 +338,18 @@ impl<E: EthSpec> Multiplexer<E> {
 
         let fork_name = self.spec.fork_name_at_slot::<E>(slot);
 
-        // TODO: Fulu
+        // Fulu re-uses `engine_newPayloadV4`, so we rely on the fork to distinguish Electra and
+        // Fulu payloads.
         let payload = if method == ENGINE_NEW_PAYLOAD_V1 || fork_name == ForkName::Bellatrix {
             serde_json::from_value(payload_json).map(JsonExecutionPayload::V1)
         } else if method == ENGINE_NEW_PAYLOAD_V2 || fork_name == ForkName::Capella {
             serde_json::from_value(payload_json).map(JsonExecutionPayload::V2)
         } else if method == ENGINE_NEW_PAYLOAD_V3 || fork_name == ForkName::Deneb {
             serde_json::from_value(payload_json).map(JsonExecutionPayload::V3)
-        } else {
+        } else if fork_name == ForkName::Electra {
             serde_json::from_value(payload_json).map(JsonExecutionPayload::V4)
+        } else {
+            serde_json::from_value(payload_json).map(JsonExecutionPayload::V5)
         }
         .map_err(|e| ErrorResponse::parse_error(id.clone(), e))?;
 
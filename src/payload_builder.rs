@@ -1,24 +1,30 @@
 use crate::{
     base_fee::expected_base_fee_per_gas,
+    metrics,
+    scheduler::Priority,
     types::{
         JsonBlobsBundleV1, JsonExecutionPayload, JsonGetPayloadResponseV1,
         JsonGetPayloadResponseV2, JsonGetPayloadResponseV3, JsonGetPayloadResponseV4,
-        JsonPayloadStatusV1Status, PayloadId, TransparentJsonPayloadId,
+        JsonGetPayloadResponseV5, JsonPayloadStatusV1Status, JsonValue, PayloadId,
+        TransparentJsonPayloadId,
     },
     ErrorResponse, Multiplexer, Request, Response,
 };
 use eth2::types::{
-    BlobsBundle, EthSpec, ExecutionBlockHash, ExecutionPayload, ExecutionPayloadBellatrix,
-    ExecutionPayloadCapella, ExecutionPayloadDeneb, ExecutionPayloadElectra, FixedVector, ForkName,
-    Hash256, Uint256, Unsigned, VariableList,
+    Address, BlobsBundle, EthSpec, ExecutionBlockHash, ExecutionPayload, ExecutionPayloadBellatrix,
+    ExecutionPayloadCapella, ExecutionPayloadDeneb, ExecutionPayloadElectra, ExecutionPayloadFulu,
+    FixedVector, ForkName, Hash256, Uint256, Unsigned, VariableList,
+};
+use execution_layer::{
+    calculate_execution_block_hash, http::ENGINE_GET_PAYLOAD_TIMEOUT, PayloadAttributes,
 };
-use execution_layer::{calculate_execution_block_hash, PayloadAttributes};
 use lru::LruCache;
+use serde::{Deserialize, Serialize};
 use std::marker::PhantomData;
 use std::num::NonZeroUsize;
 
 /// Information about previously seen canonical payloads which is used for building descendant payloads.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct PayloadInfo {
     /// Execution block number.
     pub block_number: u64,
@@ -34,6 +40,19 @@ pub struct PayloadInfo {
     pub gas_limit: u64,
 }
 
+/// Payload attributes registered with the builder, as returned by the admin API.
+#[derive(Debug, Serialize)]
+pub struct RegisteredPayload {
+    pub parent_hash: ExecutionBlockHash,
+    pub timestamp: u64,
+    pub suggested_fee_recipient: Address,
+    pub payload_id: TransparentJsonPayloadId,
+    /// ID of the real payload being built by the EL, if any.
+    pub el_payload_id: Option<TransparentJsonPayloadId>,
+    /// Whether a dummy payload has been built for these attributes.
+    pub built: bool,
+}
+
 pub struct PayloadBuilder<E: EthSpec> {
     next_payload_id: u64,
     payload_attributes: LruCache<(ExecutionBlockHash, PayloadAttributes), PayloadId>,
@@ -41,6 +60,8 @@ pub struct PayloadBuilder<E: EthSpec> {
     payload_info: LruCache<ExecutionBlockHash, PayloadInfo>,
     /// Map from payload ID to dummy execution payload.
     payloads: LruCache<PayloadId, ExecutionPayload<E>>,
+    /// Map from our payload ID to the payload ID of a real payload being built by the EL.
+    el_payload_ids: LruCache<PayloadId, PayloadId>,
     extra_data: VariableList<u8, E::MaxExtraDataBytes>,
     _phantom: PhantomData<E>,
 }
@@ -56,10 +77,81 @@ impl<E: EthSpec> PayloadBuilder<E> {
             payload_attributes: LruCache::new(cache_size),
             payload_info: LruCache::new(cache_size),
             payloads: LruCache::new(cache_size),
+            el_payload_ids: LruCache::new(cache_size),
             extra_data,
             _phantom: PhantomData,
         }
     }
+
+    /// Number of entries in each of the builder's caches, for metrics.
+    pub fn cache_lens(&self) -> [(&'static str, usize); 4] {
+        [
+            ("payload_attributes", self.payload_attributes.len()),
+            ("payload_info", self.payload_info.len()),
+            ("payloads", self.payloads.len()),
+            ("el_payload_ids", self.el_payload_ids.len()),
+        ]
+    }
+
+    /// Canonical payload info from least to most recently used, for persistence.
+    pub fn payload_info_entries(&self) -> Vec<(ExecutionBlockHash, PayloadInfo)> {
+        self.payload_info
+            .iter()
+            .rev()
+            .map(|(block_hash, info)| (*block_hash, *info))
+            .collect()
+    }
+
+    pub fn restore_payload_info(&mut self, entries: Vec<(ExecutionBlockHash, PayloadInfo)>) {
+        for (block_hash, info) in entries {
+            self.payload_info.put(block_hash, info);
+        }
+    }
+
+    /// Registered payload attributes from most to least recently used.
+    pub fn registered_payloads(&self) -> Vec<RegisteredPayload> {
+        self.payload_attributes
+            .iter()
+            .map(|((parent_hash, attributes), id)| RegisteredPayload {
+                parent_hash: *parent_hash,
+                timestamp: attributes.timestamp(),
+                suggested_fee_recipient: attributes.suggested_fee_recipient(),
+                payload_id: TransparentJsonPayloadId(*id),
+                el_payload_id: self
+                    .el_payload_ids
+                    .peek(id)
+                    .copied()
+                    .map(TransparentJsonPayloadId),
+                built: self.payloads.contains(id),
+            })
+            .collect()
+    }
+
+    /// Forget the attributes and payloads for a payload ID, returning `false` if it is unknown.
+    pub fn evict_payload(&mut self, id: &PayloadId) -> bool {
+        let keys = self
+            .payload_attributes
+            .iter()
+            .filter(|(_, registered_id)| *registered_id == id)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in &keys {
+            self.payload_attributes.pop(key);
+        }
+        let built = self.payloads.pop(id).is_some();
+        let el_payload = self.el_payload_ids.pop(id).is_some();
+        !keys.is_empty() || built || el_payload
+    }
+
+    /// Forget all registered attributes and payloads.
+    ///
+    /// Info about canonical payloads is kept, so that payloads can still be built once the
+    /// attributes are re-sent.
+    pub fn clear_payloads(&mut self) {
+        self.payload_attributes.clear();
+        self.payloads.clear();
+        self.el_payload_ids.clear();
+    }
 }
 
 impl<E: EthSpec> Multiplexer<E> {
@@ -205,9 +297,31 @@ impl<E: EthSpec> Multiplexer<E> {
                     excess_blob_gas,
                 })
             }
-            // TODO: Fulu
             ForkName::Fulu => {
-                return Err("Fulu not supported".to_string());
+                let withdrawals = payload_attributes
+                    .withdrawals()
+                    .map_err(|_| "no withdrawals".to_string())?
+                    .clone()
+                    .into();
+                ExecutionPayload::Fulu(ExecutionPayloadFulu {
+                    parent_hash,
+                    fee_recipient,
+                    state_root,
+                    receipts_root,
+                    logs_bloom,
+                    prev_randao,
+                    block_number,
+                    gas_limit,
+                    gas_used,
+                    timestamp,
+                    extra_data,
+                    base_fee_per_gas,
+                    block_hash,
+                    transactions,
+                    withdrawals,
+                    blob_gas_used,
+                    excess_blob_gas,
+                })
             }
             ForkName::Base | ForkName::Altair => return Err(format!("invalid fork: {fork_name}")),
         };
@@ -217,9 +331,7 @@ impl<E: EthSpec> Multiplexer<E> {
             | ForkName::Bellatrix
             | ForkName::Capella
             | ForkName::Deneb => None,
-            ForkName::Electra => Some(Default::default()),
-            // TODO: Fulu
-            ForkName::Fulu => None,
+            ForkName::Electra | ForkName::Fulu => Some(Default::default()),
         };
 
         let (block_hash, _) = calculate_execution_block_hash(
@@ -236,20 +348,6 @@ impl<E: EthSpec> Multiplexer<E> {
         Ok(id)
     }
 
-    pub async fn get_existing_payload_id(
-        &self,
-        parent_hash: ExecutionBlockHash,
-        payload_attributes: PayloadAttributes,
-    ) -> Result<PayloadId, String> {
-        self.payload_builder
-            .lock()
-            .await
-            .payload_attributes
-            .get(&(parent_hash, payload_attributes))
-            .copied()
-            .ok_or_else(|| format!("no payload ID known for parent {parent_hash:?}"))
-    }
-
     /// Track a payload from the canonical chain.
     pub async fn register_canonical_payload(
         &self,
@@ -288,8 +386,71 @@ impl<E: EthSpec> Multiplexer<E> {
             })
     }
 
-    pub async fn handle_get_payload(&self, request: Request) -> Result<Response, ErrorResponse> {
+    /// Allocate a payload ID without building a dummy payload, for a payload built only by the EL.
+    pub async fn allocate_payload_id(&self) -> PayloadId {
+        let mut builder = self.payload_builder.lock().await;
+        let id = builder.next_payload_id.to_be_bytes();
+        builder.next_payload_id += 1;
+        id
+    }
+
+    /// Record the EL's payload ID for a payload that it is building on our behalf.
+    pub async fn register_el_payload_id(&self, payload_id: PayloadId, el_payload_id: PayloadId) {
+        self.payload_builder
+            .lock()
+            .await
+            .el_payload_ids
+            .put(payload_id, el_payload_id);
+    }
+
+    /// Fetch a real payload from the EL using the same `engine_getPayloadVx` method as the client.
+    async fn get_payload_from_el(
+        &self,
+        method: &str,
+        el_payload_id: PayloadId,
+        priority: Priority,
+    ) -> Result<JsonValue, String> {
+        let _permit = self.scheduler.acquire(priority).await?;
+        let _timer = metrics::upstream_timer(method);
+        let params = serde_json::json!([TransparentJsonPayloadId(el_payload_id)]);
+        self.engine()
+            .api
+            .rpc_request(method, params, ENGINE_GET_PAYLOAD_TIMEOUT)
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    pub async fn handle_get_payload(
+        &self,
+        request: Request,
+        priority: Priority,
+    ) -> Result<Response, ErrorResponse> {
+        let method = request.method.clone();
         let (id, (payload_id,)) = request.parse_as::<(TransparentJsonPayloadId,)>()?;
+
+        // Serve the EL's payload if it is building one for this payload ID.
+        let el_payload_id = self
+            .payload_builder
+            .lock()
+            .await
+            .el_payload_ids
+            .get(&payload_id.0)
+            .copied();
+        if let Some(el_payload_id) = el_payload_id {
+            match self
+                .get_payload_from_el(&method, el_payload_id, priority)
+                .await
+            {
+                Ok(response) => return Response::new(id, response),
+                Err(e) => {
+                    tracing::warn!(
+                        error = ?e,
+                        "unable to get payload from EL, falling back to dummy payload"
+                    );
+                }
+            }
+        }
+
         let payload = match self.get_payload(payload_id.into()).await {
             Ok(payload) => payload,
             Err(message) => return Err(ErrorResponse::unknown_payload(id, message)),
@@ -339,9 +500,22 @@ impl<E: EthSpec> Multiplexer<E> {
                     },
                 )
             }
-            // TODO: Fulu
-            JsonExecutionPayload::V5(_) => {
-                panic!("Fulu not supported")
+            JsonExecutionPayload::V5(execution_payload) => {
+                // Our dummy payloads never contain blob transactions, so the bundle is empty and
+                // there are no cell proofs to compute.
+                let blobs_bundle = JsonBlobsBundleV1::from(BlobsBundle::default());
+                let should_override_builder = false;
+                let execution_requests = Default::default();
+                Response::new(
+                    id,
+                    JsonGetPayloadResponseV5 {
+                        execution_payload,
+                        block_value,
+                        blobs_bundle,
+                        should_override_builder,
+                        execution_requests,
+                    },
+                )
             }
         }
     }
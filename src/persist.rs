@@ -0,0 +1,199 @@
+//! Optional on-disk snapshot of the multiplexer's caches.
+//!
+//! The snapshot allows a restarted eleel to serve definite statuses to clients immediately, rather
+//! than waiting for the controller to send fresh messages. It is written periodically and on
+//! shutdown, and loaded at startup.
+use crate::{
+    multiplexer::{Multiplexer, NewPayloadCacheEntry},
+    payload_builder::PayloadInfo,
+    types::{JsonForkchoiceStateV1, JsonPayloadStatusV1, TaskExecutor},
+};
+use eth2::types::{EthSpec, ExecutionBlockHash};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Version of the snapshot format. Snapshots with a different version are ignored.
+const SNAPSHOT_VERSION: u64 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    version: u64,
+    /// Genesis time of the network, used to detect snapshots from a different network.
+    genesis_time: u64,
+    fcu: Vec<(JsonForkchoiceStateV1, JsonPayloadStatusV1)>,
+    new_payload: Vec<(ExecutionBlockHash, JsonPayloadStatusV1, u64)>,
+    justified_blocks: Vec<ExecutionBlockHash>,
+    finalized_blocks: Vec<ExecutionBlockHash>,
+    payload_info: Vec<(ExecutionBlockHash, PayloadInfo)>,
+}
+
+/// Entries of an LRU cache from least to most recently used, so that they can be restored in
+/// the same order.
+fn lru_entries<K: Hash + Eq + Clone, V, T>(
+    cache: &LruCache<K, V>,
+    f: impl Fn(&K, &V) -> T,
+) -> Vec<T> {
+    cache.iter().rev().map(|(k, v)| f(k, v)).collect()
+}
+
+impl<E: EthSpec> Multiplexer<E> {
+    async fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            genesis_time: self.genesis_time,
+            fcu: lru_entries(&*self.fcu_cache.lock().await, |fcu, status| {
+                (fcu.clone(), status.clone())
+            }),
+            new_payload: lru_entries(&*self.new_payload_cache.lock().await, |hash, entry| {
+                (*hash, entry.status.clone(), entry.block_number)
+            }),
+            justified_blocks: lru_entries(&*self.justified_block_cache.lock().await, |hash, _| {
+                *hash
+            }),
+            finalized_blocks: lru_entries(&*self.finalized_block_cache.lock().await, |hash, _| {
+                *hash
+            }),
+            payload_info: self.payload_builder.lock().await.payload_info_entries(),
+        }
+    }
+
+    /// Write a snapshot to `--persist-path`, if set.
+    ///
+    /// The snapshot is written to a temporary file which is then renamed, so that a crash while
+    /// writing never leaves a corrupt snapshot behind.
+    pub async fn save_snapshot(&self) -> Result<(), String> {
+        let Some(path) = &self.config.persist_path else {
+            return Ok(());
+        };
+        let _lock = self.persist_lock.lock().await;
+
+        let snapshot = self.snapshot().await;
+        let bytes = serde_json::to_vec(&snapshot)
+            .map_err(|e| format!("unable to encode snapshot: {e:?}"))?;
+
+        let write_path = path.clone();
+        tokio::task::spawn_blocking(move || write_snapshot(&write_path, &bytes))
+            .await
+            .map_err(|e| format!("snapshot task failed: {e}"))??;
+
+        tracing::debug!(path = %path.display(), "saved snapshot");
+        Ok(())
+    }
+
+    /// Load a snapshot from `--persist-path`, if set and present.
+    pub async fn load_snapshot(&self) -> Result<(), String> {
+        let Some(path) = &self.config.persist_path else {
+            return Ok(());
+        };
+        let Some(snapshot) = read_snapshot(path)? else {
+            tracing::info!(path = %path.display(), "no snapshot found");
+            return Ok(());
+        };
+
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "snapshot version {} is not supported (expected {SNAPSHOT_VERSION})",
+                snapshot.version
+            ));
+        }
+        if snapshot.genesis_time != self.genesis_time {
+            return Err(format!(
+                "snapshot genesis time {} does not match network genesis time {}",
+                snapshot.genesis_time, self.genesis_time
+            ));
+        }
+
+        let mut fcu_cache = self.fcu_cache.lock().await;
+        for (fcu, status) in snapshot.fcu {
+            fcu_cache.put(fcu, status);
+        }
+        drop(fcu_cache);
+
+        let mut new_payload_cache = self.new_payload_cache.lock().await;
+        for (block_hash, status, block_number) in snapshot.new_payload {
+            new_payload_cache.put(
+                block_hash,
+                NewPayloadCacheEntry {
+                    status,
+                    block_number,
+                },
+            );
+        }
+        drop(new_payload_cache);
+
+        let mut justified_block_cache = self.justified_block_cache.lock().await;
+        for block_hash in snapshot.justified_blocks {
+            justified_block_cache.put(block_hash, ());
+        }
+        drop(justified_block_cache);
+
+        let mut finalized_block_cache = self.finalized_block_cache.lock().await;
+        for block_hash in snapshot.finalized_blocks {
+            finalized_block_cache.put(block_hash, ());
+        }
+        drop(finalized_block_cache);
+
+        self.payload_builder
+            .lock()
+            .await
+            .restore_payload_info(snapshot.payload_info);
+
+        tracing::info!(path = %path.display(), "loaded snapshot");
+        Ok(())
+    }
+
+    /// Save a snapshot every `--persist-interval-secs`, and once more when the executor exits.
+    ///
+    /// Returns the result of the final save, so that failures can be reflected in the exit code.
+    pub async fn persist_periodically(&self, executor: TaskExecutor) -> Result<(), String> {
+        if self.config.persist_path.is_none() {
+            return Ok(());
+        }
+        let interval = Duration::from_secs(self.config.persist_interval_secs);
+        let exit = executor.exit();
+        tokio::pin!(exit);
+
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {
+                    if let Err(e) = self.save_snapshot().await {
+                        tracing::warn!(error = e, "failed to save snapshot");
+                    }
+                }
+                _ = &mut exit => {
+                    return self
+                        .save_snapshot()
+                        .await
+                        .map_err(|e| format!("failed to save snapshot on shutdown: {e}"));
+                }
+            }
+        }
+    }
+}
+
+/// Write a snapshot via a temporary file alongside `path`, then rename it into place.
+fn write_snapshot(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    // Append to the file name rather than replacing the extension, which might already be `.tmp`.
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    std::fs::write(&tmp_path, bytes)
+        .map_err(|e| format!("unable to write {}: {e}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, path)
+        .map_err(|e| format!("unable to rename snapshot to {}: {e}", path.display()))
+}
+
+fn read_snapshot(path: &Path) -> Result<Option<Snapshot>, String> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("unable to read {}: {e}", path.display())),
+    };
+    serde_json::from_slice(&bytes)
+        .map(Some)
+        .map_err(|e| format!("invalid snapshot at {}: {e:?}", path.display()))
+}
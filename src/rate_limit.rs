@@ -0,0 +1,256 @@
+//! Per-client rate limiting and concurrency caps.
+use crate::method_pattern::glob_match;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use strum::IntoStaticStr;
+
+/// Coarse classification of methods by their cost to the shared execution engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize, IntoStaticStr)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum MethodClass {
+    /// Engine methods answered by Eleel itself (newPayload, forkchoiceUpdated, getPayload).
+    Engine,
+    /// Cheap methods proxied to the execution engine.
+    Proxy,
+    /// Expensive methods proxied to the execution engine (`eth_call`, `eth_getLogs`, etc).
+    Heavy,
+}
+
+impl MethodClass {
+    pub fn of(method: &str) -> Self {
+        const HEAVY: &[&str] = &["eth_call", "eth_getLogs", "engine_getPayloadBodiesBy*"];
+        const ENGINE: &[&str] = &[
+            "engine_newPayloadV*",
+            "engine_forkchoiceUpdatedV*",
+            "engine_getPayloadV*",
+        ];
+
+        if HEAVY.iter().any(|pattern| glob_match(pattern, method)) {
+            Self::Heavy
+        } else if ENGINE.iter().any(|pattern| glob_match(pattern, method)) {
+            Self::Engine
+        } else {
+            Self::Proxy
+        }
+    }
+}
+
+/// Limits applied to a single client for a single class of methods.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    /// Sustained number of requests per second.
+    pub requests_per_second: Option<f64>,
+    /// Maximum number of requests that may be made in a burst (defaults to one second's worth).
+    pub burst: Option<u32>,
+    /// Maximum number of requests that may be in flight at once.
+    pub max_in_flight: Option<usize>,
+}
+
+impl Limits {
+    /// Check that the limits admit at least some requests.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(requests_per_second) = self.requests_per_second {
+            if !(requests_per_second.is_finite() && requests_per_second > 0.0) {
+                return Err(format!(
+                    "requests_per_second must be positive, got {requests_per_second}"
+                ));
+            }
+        }
+        if self.burst == Some(0) {
+            return Err("burst must be at least 1".into());
+        }
+        if self.max_in_flight == Some(0) {
+            return Err("max_in_flight must be at least 1".into());
+        }
+        Ok(())
+    }
+
+    fn burst(&self, requests_per_second: f64) -> f64 {
+        self.burst
+            .map(f64::from)
+            .unwrap_or_else(|| requests_per_second.max(1.0))
+    }
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<(String, MethodClass), Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    in_flight: usize,
+}
+
+/// Guard representing a request in flight, which releases its slot when dropped.
+pub struct InFlightGuard<'a> {
+    limiter: &'a RateLimiter,
+    key: (String, MethodClass),
+}
+
+impl RateLimiter {
+    /// Attempt to start a request, returning an error immediately if the client is over budget.
+    ///
+    /// Limits are passed in on each call so that they may be changed by a secrets reload.
+    pub fn try_acquire(
+        &self,
+        client_id: &str,
+        class: MethodClass,
+        limits: &Limits,
+    ) -> Result<InFlightGuard<'_>, String> {
+        let key = (client_id.to_string(), class);
+        let class_name: &str = class.into();
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.clone()).or_insert_with(|| Bucket {
+            tokens: f64::INFINITY,
+            last_refill: now,
+            in_flight: 0,
+        });
+
+        if let Some(max_in_flight) = limits.max_in_flight {
+            if bucket.in_flight >= max_in_flight {
+                return Err(format!(
+                    "too many concurrent {class_name} requests (max {max_in_flight})"
+                ));
+            }
+        }
+
+        if let Some(requests_per_second) = limits.requests_per_second {
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * requests_per_second)
+                .min(limits.burst(requests_per_second));
+            bucket.last_refill = now;
+
+            if bucket.tokens < 1.0 {
+                return Err(format!(
+                    "rate limit of {requests_per_second} {class_name} requests per second exceeded"
+                ));
+            }
+            bucket.tokens -= 1.0;
+        }
+
+        bucket.in_flight += 1;
+
+        Ok(InFlightGuard { limiter: self, key })
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(bucket) = self.limiter.buckets.lock().unwrap().get_mut(&self.key) {
+            bucket.in_flight = bucket.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn method_classes() {
+        assert_eq!(MethodClass::of("engine_newPayloadV4"), MethodClass::Engine);
+        assert_eq!(MethodClass::of("eth_getLogs"), MethodClass::Heavy);
+        assert_eq!(
+            MethodClass::of("engine_getPayloadBodiesByRangeV1"),
+            MethodClass::Heavy
+        );
+        assert_eq!(MethodClass::of("eth_blockNumber"), MethodClass::Proxy);
+    }
+
+    #[test]
+    fn unlimited() {
+        let limiter = RateLimiter::default();
+        let limits = Limits::default();
+        let guards = (0..100)
+            .map(|_| limiter.try_acquire("node1", MethodClass::Proxy, &limits))
+            .collect::<Result<Vec<_>, _>>();
+        assert!(guards.is_ok());
+    }
+
+    #[test]
+    fn max_in_flight() {
+        let limiter = RateLimiter::default();
+        let limits = Limits {
+            max_in_flight: Some(1),
+            ..Limits::default()
+        };
+
+        let guard = limiter.try_acquire("node1", MethodClass::Heavy, &limits);
+        assert!(guard.is_ok());
+        assert!(limiter
+            .try_acquire("node1", MethodClass::Heavy, &limits)
+            .is_err());
+
+        // Other clients and classes are unaffected.
+        assert!(limiter
+            .try_acquire("node2", MethodClass::Heavy, &limits)
+            .is_ok());
+        assert!(limiter
+            .try_acquire("node1", MethodClass::Proxy, &limits)
+            .is_ok());
+
+        drop(guard);
+        assert!(limiter
+            .try_acquire("node1", MethodClass::Heavy, &limits)
+            .is_ok());
+    }
+
+    #[test]
+    fn requests_per_second() {
+        let limiter = RateLimiter::default();
+        let limits = Limits {
+            requests_per_second: Some(0.001),
+            burst: Some(2),
+            ..Limits::default()
+        };
+
+        for _ in 0..2 {
+            assert!(limiter
+                .try_acquire("node1", MethodClass::Proxy, &limits)
+                .is_ok());
+        }
+        assert!(limiter
+            .try_acquire("node1", MethodClass::Proxy, &limits)
+            .is_err());
+        assert!(limiter
+            .try_acquire("node2", MethodClass::Proxy, &limits)
+            .is_ok());
+    }
+
+    #[test]
+    fn invalid_limits() {
+        let valid = Limits {
+            requests_per_second: Some(0.5),
+            burst: Some(1),
+            max_in_flight: Some(1),
+        };
+        assert!(valid.validate().is_ok());
+        assert!(Limits::default().validate().is_ok());
+
+        for requests_per_second in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let limits = Limits {
+                requests_per_second: Some(requests_per_second),
+                ..valid
+            };
+            assert!(limits.validate().is_err(), "{requests_per_second}");
+        }
+        let limits = Limits {
+            burst: Some(0),
+            ..valid
+        };
+        assert!(limits.validate().is_err());
+        let limits = Limits {
+            max_in_flight: Some(0),
+            ..valid
+        };
+        assert!(limits.validate().is_err());
+    }
+}
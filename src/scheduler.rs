@@ -0,0 +1,254 @@
+//! Prioritised access to the execution engine.
+//!
+//! Requests to the EL must acquire a permit from the scheduler. Controller requests are always
+//! served before queued client requests, and some slots are reserved for the controller so that
+//! heavy client traffic can never delay the canonical chain.
+use crate::{
+    metrics,
+    multiplexer::Multiplexer,
+    types::{ErrorResponse, JsonValue},
+};
+use eth2::types::EthSpec;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use strum::IntoStaticStr;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+/// Number of concurrent request slots that may only be used by the controller.
+pub const CONTROLLER_RESERVED_SLOTS: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Priority {
+    Controller,
+    Client,
+}
+
+pub struct Scheduler {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    max_queue_len: usize,
+}
+
+struct State {
+    /// Number of free slots.
+    available: usize,
+    controller_queue: VecDeque<oneshot::Sender<Permit>>,
+    client_queue: VecDeque<oneshot::Sender<Permit>>,
+}
+
+/// Permission to send a request to the EL, which frees its slot when dropped.
+pub struct Permit {
+    inner: Arc<Inner>,
+    armed: bool,
+}
+
+impl Scheduler {
+    pub fn new(max_concurrent: usize, max_queue_len: usize) -> Result<Self, String> {
+        if max_concurrent <= CONTROLLER_RESERVED_SLOTS {
+            return Err(format!(
+                "EL concurrency must be greater than {CONTROLLER_RESERVED_SLOTS}"
+            ));
+        }
+        Ok(Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    available: max_concurrent,
+                    controller_queue: VecDeque::new(),
+                    client_queue: VecDeque::new(),
+                }),
+                max_queue_len,
+            }),
+        })
+    }
+
+    /// Wait for a slot to send a request to the EL.
+    ///
+    /// Returns an error immediately if the queue for this priority is full.
+    pub async fn acquire(&self, priority: Priority) -> Result<Permit, String> {
+        let start = Instant::now();
+
+        let rx = {
+            let mut state = self.inner.state.lock().unwrap();
+            let reserved = match priority {
+                Priority::Controller => 0,
+                Priority::Client => CONTROLLER_RESERVED_SLOTS,
+            };
+            if state.available > reserved {
+                state.available -= 1;
+                metrics::observe_queue_delay(priority.into(), start.elapsed());
+                return Ok(Permit {
+                    inner: self.inner.clone(),
+                    armed: true,
+                });
+            }
+
+            let queue = match priority {
+                Priority::Controller => &mut state.controller_queue,
+                Priority::Client => &mut state.client_queue,
+            };
+            if queue.len() >= self.inner.max_queue_len {
+                return Err("execution engine request queue is full".into());
+            }
+            let (tx, rx) = oneshot::channel();
+            queue.push_back(tx);
+            rx
+        };
+
+        let permit = rx
+            .await
+            .map_err(|_| "execution engine scheduler dropped request".to_string())?;
+        metrics::observe_queue_delay(priority.into(), start.elapsed());
+        Ok(permit)
+    }
+}
+
+impl Inner {
+    /// Hand a freed slot to the next waiter, or return it to the pool.
+    fn release(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+
+        while let Some(tx) = state.controller_queue.pop_front() {
+            match tx.send(self.new_permit()) {
+                Ok(()) => return,
+                // Waiter gone (e.g. request cancelled), try the next one.
+                Err(mut permit) => permit.armed = false,
+            }
+        }
+
+        // Clients may only take the slot if it leaves the reserved slots free.
+        while state.available >= CONTROLLER_RESERVED_SLOTS {
+            let Some(tx) = state.client_queue.pop_front() else {
+                break;
+            };
+            match tx.send(self.new_permit()) {
+                Ok(()) => return,
+                Err(mut permit) => permit.armed = false,
+            }
+        }
+
+        state.available += 1;
+    }
+
+    fn new_permit(self: &Arc<Self>) -> Permit {
+        Permit {
+            inner: self.clone(),
+            armed: true,
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if self.armed {
+            self.inner.release();
+        }
+    }
+}
+
+impl<E: EthSpec> Multiplexer<E> {
+    /// Acquire a permit to send a request to the EL, mapping a full queue to a JSON-RPC error.
+    pub async fn acquire_engine(
+        &self,
+        id: &JsonValue,
+        priority: Priority,
+    ) -> Result<Permit, ErrorResponse> {
+        self.scheduler.acquire(priority).await.map_err(|message| {
+            tracing::warn!(?priority, message, "unable to schedule request to EL");
+            ErrorResponse::limit_exceeded(id.clone(), message)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{poll, FutureExt};
+    use std::task::Poll;
+
+    fn is_granted(result: &Option<Result<Permit, String>>) -> bool {
+        matches!(result, Some(Ok(_)))
+    }
+
+    #[tokio::test]
+    async fn reserved_slot_only_for_controller() {
+        let scheduler = Scheduler::new(2, 4).unwrap();
+
+        let client_permit = scheduler.acquire(Priority::Client).now_or_never();
+        assert!(is_granted(&client_permit));
+
+        // The remaining slot is reserved for the controller.
+        let mut client = Box::pin(scheduler.acquire(Priority::Client));
+        assert!(poll!(&mut client).is_pending());
+        assert!(is_granted(
+            &scheduler.acquire(Priority::Controller).now_or_never()
+        ));
+    }
+
+    #[tokio::test]
+    async fn controller_served_before_queued_clients() {
+        let scheduler = Scheduler::new(2, 4).unwrap();
+        let first = scheduler.acquire(Priority::Controller).await.unwrap();
+        let second = scheduler.acquire(Priority::Controller).await.unwrap();
+
+        // The client queues before the controller.
+        let mut client = Box::pin(scheduler.acquire(Priority::Client));
+        assert!(poll!(&mut client).is_pending());
+        let mut controller = Box::pin(scheduler.acquire(Priority::Controller));
+        assert!(poll!(&mut controller).is_pending());
+
+        drop(first);
+        let controller = match poll!(&mut controller) {
+            Poll::Ready(Ok(permit)) => permit,
+            _ => panic!("controller should be served first"),
+        };
+        assert!(poll!(&mut client).is_pending());
+
+        // The client can't take the last free slot, which is reserved.
+        drop(second);
+        assert!(poll!(&mut client).is_pending());
+
+        drop(controller);
+        assert!(matches!(poll!(&mut client), Poll::Ready(Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn full_queue_is_an_error() {
+        let scheduler = Scheduler::new(2, 1).unwrap();
+        let _permit = scheduler.acquire(Priority::Client).await.unwrap();
+
+        let mut queued = Box::pin(scheduler.acquire(Priority::Client));
+        assert!(poll!(&mut queued).is_pending());
+
+        let result = scheduler.acquire(Priority::Client).now_or_never();
+        assert!(matches!(result, Some(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiters_do_not_leak_slots() {
+        let scheduler = Scheduler::new(2, 4).unwrap();
+        let first = scheduler.acquire(Priority::Controller).await.unwrap();
+        let second = scheduler.acquire(Priority::Controller).await.unwrap();
+
+        // Cancelled before being handed a permit.
+        let mut waiter = Box::pin(scheduler.acquire(Priority::Controller));
+        assert!(poll!(&mut waiter).is_pending());
+        drop(waiter);
+        drop(first);
+        let first = scheduler.acquire(Priority::Controller).now_or_never();
+        assert!(is_granted(&first));
+
+        // Cancelled after being handed a permit, but before receiving it.
+        let mut waiter = Box::pin(scheduler.acquire(Priority::Controller));
+        assert!(poll!(&mut waiter).is_pending());
+        drop(second);
+        drop(waiter);
+        assert!(is_granted(
+            &scheduler.acquire(Priority::Controller).now_or_never()
+        ));
+    }
+}
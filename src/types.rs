@@ -9,15 +9,15 @@ pub use execution_layer::{
     json_structures::{
         JsonBlobsBundleV1, JsonExecutionPayload, JsonExecutionRequests,
         JsonForkchoiceUpdatedV1Response, JsonGetPayloadResponseV1, JsonGetPayloadResponseV2,
-        JsonGetPayloadResponseV3, JsonGetPayloadResponseV4, JsonPayloadAttributes,
-        JsonPayloadAttributesV2, JsonPayloadStatusV1, JsonPayloadStatusV1Status,
+        JsonGetPayloadResponseV3, JsonGetPayloadResponseV4, JsonGetPayloadResponseV5,
+        JsonPayloadAttributes, JsonPayloadStatusV1, JsonPayloadStatusV1Status,
         TransparentJsonPayloadId,
     },
     NewPayloadRequest, NewPayloadRequestBellatrix, NewPayloadRequestCapella,
-    NewPayloadRequestDeneb, NewPayloadRequestElectra,
+    NewPayloadRequestDeneb, NewPayloadRequestElectra, NewPayloadRequestFulu,
 };
 pub use serde_json::Value as JsonValue;
-pub use task_executor::TaskExecutor;
+pub use task_executor::{ShutdownReason, TaskExecutor};
 
 pub type PayloadId = [u8; 8];
 
@@ -58,6 +58,8 @@ pub enum ErrorCode {
     InvalidParams = -32602,
     InternalError = -32603,
     ServerError = -32000,
+    Unauthorized = -32001,
+    LimitExceeded = -32005,
     UnknownPayload = -38001,
     InvalidForkChoiceState = -38002,
     InvalidPayloadAttributes = -38003,
@@ -167,6 +169,35 @@ impl ErrorResponse {
         }
     }
 
+    pub fn unauthorized(id: JsonValue, message: String) -> Self {
+        Self {
+            jsonrpc: "2.0".into(),
+            id,
+            error: JsonError {
+                code: ErrorCode::Unauthorized,
+                message,
+            },
+        }
+    }
+
+    pub fn method_not_allowed(id: JsonValue, method: &str) -> Self {
+        Self::unauthorized(
+            id,
+            format!("method `{method}` not permitted for this client"),
+        )
+    }
+
+    pub fn limit_exceeded(id: JsonValue, message: String) -> Self {
+        Self {
+            jsonrpc: "2.0".into(),
+            id,
+            error: JsonError {
+                code: ErrorCode::LimitExceeded,
+                message,
+            },
+        }
+    }
+
     pub fn parse_error(id: JsonValue, error: serde_json::Error) -> Self {
         Self::parse_error_generic(id, format!("parse error: {error:?}"))
     }
@@ -204,6 +235,17 @@ impl ErrorResponse {
         }
     }
 
+    pub fn too_large_request(id: JsonValue, message: String) -> Self {
+        Self {
+            jsonrpc: "2.0".into(),
+            id,
+            error: JsonError {
+                code: ErrorCode::TooLargeRequest,
+                message,
+            },
+        }
+    }
+
     pub fn unknown_payload(id: JsonValue, message: String) -> Self {
         Self {
             jsonrpc: "2.0".into(),
@@ -0,0 +1,49 @@
+//! Wake-ups for clients waiting on a response from the controller.
+//!
+//! Rather than polling the caches, client handlers subscribe to a key (e.g. a block hash) and are
+//! woken as soon as the controller path caches a new status for that key.
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use tokio::sync::watch;
+use tokio::time::{timeout_at, Instant};
+
+pub struct Waiters<K> {
+    channels: Mutex<HashMap<K, watch::Sender<()>>>,
+}
+
+impl<K: Hash + Eq> Waiters<K> {
+    pub fn new() -> Self {
+        Self {
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Subscribe to notifications for `key`.
+    ///
+    /// Callers should subscribe *before* checking the relevant cache, so that a notification
+    /// arriving between the check and the wait is not missed.
+    pub fn subscribe(&self, key: K) -> watch::Receiver<()> {
+        let mut channels = self.channels.lock().unwrap();
+
+        // Prune channels that nobody is waiting on anymore.
+        channels.retain(|_, tx| tx.receiver_count() > 0);
+
+        channels
+            .entry(key)
+            .or_insert_with(|| watch::channel(()).0)
+            .subscribe()
+    }
+
+    /// Wake all clients waiting on `key`.
+    pub fn notify(&self, key: &K) {
+        if let Some(tx) = self.channels.lock().unwrap().get(key) {
+            tx.send_replace(());
+        }
+    }
+}
+
+/// Wait for the next notification on `rx`, returning `false` if the `deadline` passes first.
+pub async fn wait_until(rx: &mut watch::Receiver<()>, deadline: Instant) -> bool {
+    matches!(timeout_at(deadline, rx.changed()).await, Ok(Ok(())))
+}